@@ -32,6 +32,23 @@ export class CdkStack extends cdk.Stack {
       removalPolicy: cdk.RemovalPolicy.DESTROY,
     });
 
+    //郵便番号ごとの住所の履歴を保存するテーブル
+    // パーティションキーを郵便番号、ソートキーを住所が置き換えられた更新の実行日時(YYYY-MM-DDTHH:MM:SS.sssZ)とする
+    // 置き換えたデータセットのバージョンは valid_until 属性に保存する
+    const historyTable = new cdk.aws_dynamodb.Table(this, 'HistoryTable', {
+      tableName: `postal-code-history-${stage}`,
+      partitionKey: {
+        type: cdk.aws_dynamodb.AttributeType.STRING,
+        name: 'postal_code',
+      },
+      sortKey: {
+        type: cdk.aws_dynamodb.AttributeType.STRING,
+        name: 'superseded_at',
+      },
+      billingMode: BillingMode.PAY_PER_REQUEST,
+      removalPolicy: cdk.RemovalPolicy.DESTROY,
+    });
+
     //role for lambda
     const role = new cdk.aws_iam.Role(this, 'RustLambdaRole', {
       roleName: `rust-lambda-role-${stage}`,
@@ -40,11 +57,12 @@ export class CdkStack extends cdk.Stack {
       inlinePolicies: {
         UserTablePut: new cdk.aws_iam.PolicyDocument({
           statements: [new cdk.aws_iam.PolicyStatement({
-            actions: ['dynamodb:GetItem', 'dynamodb:PutItem', 'dynamodb:BatchWriteItem'],
+            actions: ['dynamodb:GetItem', 'dynamodb:PutItem', 'dynamodb:BatchGetItem', 'dynamodb:BatchWriteItem', 'dynamodb:Query'],
             effect: cdk.aws_iam.Effect.ALLOW,
             resources: [
               `arn:aws:dynamodb:${this.region}:${this.account}:table/postal-codes-*`,
               `arn:aws:dynamodb:${this.region}:${this.account}:table/hash-table-*`,
+              `arn:aws:dynamodb:${this.region}:${this.account}:table/postal-code-history-*`,
             ]
           })]
         })
@@ -60,6 +78,7 @@ export class CdkStack extends cdk.Stack {
       description: "Get PostalCode Information from DynamoDB",
       environment: {
         POSTAL_CODE_TABLE: postalCodes.tableName,
+        HISTORY_TABLE: historyTable.tableName,
       },
       role: role,
     });
@@ -72,6 +91,11 @@ export class CdkStack extends cdk.Stack {
       environment: {
         POSTAL_CODE_TABLE: postalCodes.tableName,
         HASH_TABLE: hashTable.tableName,
+        HISTORY_TABLE: historyTable.tableName,
+        // 取り込むken_allの形式 legacy: ken_all.zip(Shift-JIS) / utf8: utf_ken_all.zip(UTF-8)
+        KEN_ALL_FORMAT: 'legacy',
+        // 同時に実行するbatch_write_itemの数
+        BATCH_WRITE_CONCURRENCY: '8',
       },
       timeout: cdk.Duration.minutes(10),
       memorySize: 512,
@@ -84,17 +108,35 @@ export class CdkStack extends cdk.Stack {
         stageName: stage,
       }
     });
-    //POST: /postal-code
-    api.root
-      .addResource('postal-code')
-      .addResource('{postalCode}')
+    const requestValidator = api.addRequestValidator('postal-code-validator', {
+      validateRequestParameters: true,
+    });
+    //GET: /postal-code?postalCode=...
+    // 郵便番号の指定がない場合や形式が不正な場合は、LambdaからJSONのエラーを返すため必須にしない
+    const postalCodeRootResource = api.root.addResource('postal-code');
+    postalCodeRootResource
+      .addMethod('GET', new cdk.aws_apigateway.LambdaIntegration(getPostalCodeLambda), {
+        requestParameters: {
+          'method.request.querystring.postalCode': false,
+        },
+      });
+    //GET: /postal-code/{postalCode}
+    const postalCodeResource = postalCodeRootResource.addResource('{postalCode}');
+    postalCodeResource
       .addMethod('GET', new cdk.aws_apigateway.LambdaIntegration(getPostalCodeLambda), {
         requestParameters: {
           'method.request.path.postalCode': true,
         },
-        requestValidator: api.addRequestValidator('postal-code-validator', {
-          validateRequestParameters: true,
-        })
+        requestValidator: requestValidator,
+      });
+    //GET: /postal-code/{postalCode}/history
+    postalCodeResource
+      .addResource('history')
+      .addMethod('GET', new cdk.aws_apigateway.LambdaIntegration(getPostalCodeLambda), {
+        requestParameters: {
+          'method.request.path.postalCode': true,
+        },
+        requestValidator: requestValidator,
       });
 
 
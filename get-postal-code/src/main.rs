@@ -1,87 +1,379 @@
+mod kana;
+
+use std::collections::HashMap;
 use std::env;
 
 use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use lambda_http::aws_lambda_events::serde_json;
 use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response};
 
+use crate::kana::KanaStyle;
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct Business {
+    name: String,
+    name_kana: String,
+    address: String,
+    handling_office: String,
+    is_post_office_box: bool,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct Annotation {
+    // 括弧内の元の文字列
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    floor: Option<String>,
+    chome: Vec<String>,
+    banchi: Vec<String>,
+    // 「を除く」「以外」で終わる、除外する範囲を表す注記であればtrue
+    excluded: bool,
+    others: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct CityName {
+    // 郡に属する町村のみ郡名を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    county: Option<String>,
+    // 郡名・区名を除いた市区町村名
+    city: String,
+    // 政令指定都市のみ区名を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ward: Option<String>,
+}
+
 #[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
 struct Address {
+    // 住所の郵便番号なら"town"、大口事業所個別番号なら"business"
+    record_type: String,
     prefecture: String,
     city: String,
     town: String,
     prefecture_kana: String,
     city_kana: String,
     town_kana: String,
+    // 市区町村名(city)を郡・市区町村・政令指定都市の区に分けたもの (分割前に書き込まれた住所にはない)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    city_name: Option<CityName>,
+    // クエリパラメータでローマ字表記が要求され、ローマ字表記が保存されている場合のみ値を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prefecture_roma: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    city_roma: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    town_roma: Option<String>,
+    // 大口事業所個別番号の場合のみ事業所の情報を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    business: Option<Business>,
+    // 町域名の括弧内に注記がある場合のみ、構造化した注記を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    annotation: Option<Annotation>,
+    // 岩手県の地割で区分された町域の場合のみ、該当する地割番号の一覧を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chiwari: Option<Vec<u32>>,
+    // 取り込んだデータの更新の表示 ("no_change", "changed", "deleted")
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changed: Option<String>,
+    // 取り込んだデータの変更理由 (住所の郵便番号のみ)
+    // 0: 変更なし, 1: 市政・区政・町政・分区・政令指定都市施行, 2: 住居表示の実施, 3: 区画整理, 4: 郵便区調整等, 5: 訂正, 6: 廃止
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change_reason: Option<u8>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
 struct ResponseData {
     code: String,
+    // 住所が最後に変更されたデータセットのバージョン (YYYY-MM-DD)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dataset_version: Option<String>,
+    data: Vec<Address>,
+}
+
+/// 郵便番号の住所の履歴の一件分
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct HistoryEntry {
+    // この住所が有効になったデータセットのバージョン (記録される前の住所にはない)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valid_from: Option<String>,
+    // この住所が置き換えられたデータセットのバージョン (現在の住所にはない)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valid_until: Option<String>,
     data: Vec<Address>,
 }
 
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct HistoryResponseData {
+    code: String,
+    // 古い順に並べた住所の履歴 (現在の住所が最後になる)
+    history: Vec<HistoryEntry>,
+}
+
+/// エラーの種類を表す機械判読用のコード
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    // 郵便番号が指定されていない
+    MissingPostalCode,
+    // 郵便番号が半角数字7桁でない (全角数字とハイフンは正規化してから判定する)
+    InvalidPostalCode,
+    // 郵便番号以外のクエリパラメータ(kana, roma)の値が不正
+    InvalidParameter,
+    // 郵便番号に該当する住所がない
+    NotFound,
+    // DynamoDBからの取得に失敗した
+    InternalError,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct ErrorResponseData {
+    error: ErrorCode,
+    message: String,
+}
+
 async fn function_handler(client: &Client, event: Request) -> Result<Response<Body>, Error> {
     // 環境変数から郵便番号が保存されたDynamoDBのテーブル名を取得
     let table_name = env::var("POSTAL_CODE_TABLE").expect("POSTAL_CODE_TABLE not set");
+    let history_table_name = env::var("HISTORY_TABLE").expect("HISTORY_TABLE not set");
 
     // API Gatewayから渡されたパスパラメータを取得
     let path_parameters = event.path_parameters();
     tracing::info!(path_parameters = ?path_parameters, "query");
 
-    let mut code: Option<String> = None;
-    let mut address: Option<Address> = None;
-    //パスパラメータから検索する郵便番号を取得
-    if let Some(postal_code) = path_parameters.first("postalCode") {
-        //郵便番号入力値を正規化
-        let postal_code = postal_code_normalize(postal_code);
-        tracing::info!(postal_code = ?postal_code, "Postal code");
-
-        //郵便番号をキーにしてDynamoDBから住所情報を取得
-        let item = client
-            .get_item()
-            .table_name(table_name)
-            .key("postal_code", AttributeValue::S(postal_code.to_string()))
+    //検索する郵便番号をパスパラメータから取得し、なければクエリパラメータ(/postal-code?postalCode=...)から取得
+    let query_string_parameters = event.query_string_parameters();
+    let parameters = match parse_parameters(
+        path_parameters
+            .first("postalCode")
+            .or_else(|| query_string_parameters.first("postalCode")),
+        query_string_parameters.first("kana"),
+        query_string_parameters.first("roma"),
+    ) {
+        Ok(parameters) => parameters,
+        Err((error, message)) => return error_response(400, error, message),
+    };
+    let QueryParameters {
+        postal_code,
+        kana_style,
+        with_roma,
+    } = parameters;
+    tracing::info!(postal_code = ?postal_code, "Postal code");
+
+    // /postal-code/{postalCode}/history であれば、住所の履歴を返す
+    if event.uri().path().ends_with("/history") {
+        let history = match get_history(
+            client,
+            &table_name,
+            &history_table_name,
+            &postal_code,
+            kana_style,
+            with_roma,
+        )
+        .await
+        {
+            Ok(history) => history,
+            Err(err) => {
+                tracing::error!(error = %err, "Failed to get postal code history");
+                return error_response(
+                    500,
+                    ErrorCode::InternalError,
+                    "failed to get postal code history".to_string(),
+                );
+            }
+        };
+        if history.is_empty() {
+            return error_response(
+                404,
+                ErrorCode::NotFound,
+                format!("postal code not found: {}", postal_code),
+            );
+        }
+
+        let data = HistoryResponseData {
+            code: postal_code,
+            history,
+        };
+        return json_response(200, &data);
+    }
+
+    //郵便番号をキーにしてDynamoDBから住所情報を取得
+    let item = match client
+        .get_item()
+        .table_name(table_name)
+        .key("postal_code", AttributeValue::S(postal_code.to_string()))
+        .send()
+        .await
+    {
+        Ok(item) => item,
+        Err(err) => {
+            tracing::error!(error = %err, "Failed to get postal code");
+            return error_response(
+                500,
+                ErrorCode::InternalError,
+                "failed to get postal code".to_string(),
+            );
+        }
+    };
+
+    //住所情報を一つも取得できなければ、該当する郵便番号なし
+    let (addresses, dataset_version) = match item.item() {
+        Some(record) => (
+            item_to_addresses(record, kana_style, with_roma),
+            get_string(record, "dataset_version"),
+        ),
+        None => (Vec::new(), None),
+    };
+    if addresses.is_empty() {
+        return error_response(
+            404,
+            ErrorCode::NotFound,
+            format!("postal code not found: {}", postal_code),
+        );
+    }
+
+    // 返却用のデータを作成
+    let data = ResponseData {
+        //検索に使用した入力値をレスポンスにセット
+        code: postal_code,
+        dataset_version,
+        data: addresses,
+    };
+
+    return json_response(200, &data);
+}
+
+// 郵便番号の住所の履歴を古い順に取得する
+// 履歴テーブルには置き換えられた住所が保存されているため、最後に現在の住所を加える
+async fn get_history(
+    client: &Client,
+    table_name: &str,
+    history_table_name: &str,
+    postal_code: &str,
+    kana_style: KanaStyle,
+    with_roma: bool,
+) -> Result<Vec<HistoryEntry>, Error> {
+    let mut history = Vec::<HistoryEntry>::new();
+
+    //置き換えられた更新の実行日時(ソートキー)の昇順に取得する
+    let mut exclusive_start_key = None;
+    loop {
+        let output = client
+            .query()
+            .table_name(history_table_name)
+            .key_condition_expression("postal_code = :postal_code")
+            .expression_attribute_values(":postal_code", AttributeValue::S(postal_code.to_string()))
+            .set_exclusive_start_key(exclusive_start_key)
             .send()
             .await?;
 
-        //DynamoDBから住所情報を取得出来たら、レスポンスに住所情報をセット
-        if let Some(record) = item.item() {
-            let prefecture = record.get("prefecture").unwrap().as_s().unwrap();
-            let city = record.get("city").unwrap().as_s().unwrap();
-            let town = record.get("town").unwrap().as_s().unwrap();
-            let prefecture_kana = record.get("prefecture_kana").unwrap().as_s().unwrap();
-            let city_kana = record.get("city_kana").unwrap().as_s().unwrap();
-            let town_kana = record.get("town_kana").unwrap().as_s().unwrap();
-
-            address = Some(Address {
-                prefecture: prefecture.to_string(),
-                city: city.to_string(),
-                town: town.to_string(),
-                prefecture_kana: prefecture_kana.to_string(),
-                city_kana: city_kana.to_string(),
-                town_kana: town_kana.to_string(),
+        for item in output.items().unwrap_or_default() {
+            history.push(HistoryEntry {
+                valid_from: get_string(item, "valid_from"),
+                valid_until: get_string(item, "valid_until"),
+                data: item_to_addresses(item, kana_style, with_roma),
             });
         }
 
-        //検索に使用した入力値をレスポンスにセット
-        code = Some(postal_code);
+        exclusive_start_key = output.last_evaluated_key().cloned();
+        if exclusive_start_key.is_none() {
+            break;
+        }
     }
 
-    // 返却用のデータを作成
-    let data = ResponseData {
-        code: code.unwrap_or("".to_string()),
-        data: match address {
-            Some(address) => vec![address],
-            None => vec![],
-        },
+    //現在の住所を取得
+    let item = client
+        .get_item()
+        .table_name(table_name)
+        .key("postal_code", AttributeValue::S(postal_code.to_string()))
+        .send()
+        .await?;
+    if let Some(record) = item.item() {
+        history.push(HistoryEntry {
+            valid_from: get_string(record, "dataset_version"),
+            valid_until: None,
+            data: item_to_addresses(record, kana_style, with_roma),
+        });
+    }
+
+    return Ok(history);
+}
+
+// リクエストのパラメータを検証した結果
+#[derive(std::fmt::Debug, PartialEq)]
+struct QueryParameters {
+    // 正規化した郵便番号 (半角数字7桁)
+    postal_code: String,
+    // 読み仮名の出力形式 (指定がなければ保存されている全角カタカナのまま返す)
+    kana_style: KanaStyle,
+    // ローマ字表記を返すかどうか
+    with_roma: bool,
+}
+
+// 郵便番号・読み仮名の出力形式・ローマ字表記の有無のパラメータを検証する
+// 不正なパラメータがあれば、エラーの種類とメッセージを返す
+fn parse_parameters(
+    postal_code: Option<&str>,
+    kana: Option<&str>,
+    roma: Option<&str>,
+) -> Result<QueryParameters, (ErrorCode, String)> {
+    let kana_style = match kana {
+        Some(name) => KanaStyle::from_name(name).ok_or_else(|| {
+            (
+                ErrorCode::InvalidParameter,
+                format!("kana must be hankaku, zenkaku or hiragana: {}", name),
+            )
+        })?,
+        None => KanaStyle::Zenkaku,
+    };
+
+    let with_roma = match roma {
+        Some("true" | "1") => true,
+        Some("false" | "0") | None => false,
+        Some(value) => {
+            return Err((
+                ErrorCode::InvalidParameter,
+                format!("roma must be true, false, 1 or 0: {}", value),
+            ))
+        }
+    };
+
+    let postal_code = match postal_code {
+        Some(postal_code) => postal_code_normalize(postal_code),
+        None => {
+            return Err((
+                ErrorCode::MissingPostalCode,
+                "postal code is required".to_string(),
+            ))
+        }
     };
+    if postal_code.len() != 7 || !postal_code.chars().all(|c| c.is_ascii_digit()) {
+        return Err((
+            ErrorCode::InvalidPostalCode,
+            format!("postal code must be 7 digits: {}", postal_code),
+        ));
+    }
+
+    return Ok(QueryParameters {
+        postal_code,
+        kana_style,
+        with_roma,
+    });
+}
 
+// エラーの種類とメッセージを持つJSONのエラーレスポンスを作成する
+fn error_response(status: u16, error: ErrorCode, message: String) -> Result<Response<Body>, Error> {
+    tracing::info!(status = status, error = ?error, message = message, "Error response");
+    return json_response(status, &ErrorResponseData { error, message });
+}
+
+// データ構造をJSON文字列に変換してレスポンスを作成する
+fn json_response<T: serde::Serialize>(status: u16, data: &T) -> Result<Response<Body>, Error> {
     // データ構造を返却用のJSON文字列に変換
-    let body = serde_json::to_string(&data).map_err(Box::new)?;
+    let body = serde_json::to_string(data).map_err(Box::new)?;
 
     // レスポンス作成
     let res = Response::builder()
-        .status(200)
+        .status(status)
         .header("content-type", "application/json")
         .body(body.into())
         .map_err(Box::new)?;
@@ -89,6 +381,177 @@ async fn function_handler(client: &Client, event: Request) -> Result<Response<Bo
     Ok(res)
 }
 
+// DynamoDBから取得した項目(または履歴)に含まれる全ての住所情報を返却用の住所データに変換する
+// 住所のリストや住所情報の形式が不正な場合は、その住所を読み飛ばす
+fn item_to_addresses(
+    item: &HashMap<String, AttributeValue>,
+    kana_style: KanaStyle,
+    with_roma: bool,
+) -> Vec<Address> {
+    match item.get("addresses") {
+        //郵便番号に紐づく全ての住所情報
+        Some(address_list) => match address_list.as_l() {
+            Ok(address_list) => address_list
+                .iter()
+                .filter_map(|address| match address.as_m() {
+                    Ok(address) => Some(attribute_to_address(address, kana_style, with_roma)),
+                    Err(_) => {
+                        tracing::warn!(address = ?address, "Invalid address");
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => {
+                tracing::warn!(addresses = ?address_list, "Invalid address list");
+                Vec::new()
+            }
+        },
+        //住所のリストを持たない古い形式の項目は、項目自体を一つの住所情報として扱う
+        None => vec![attribute_to_address(item, kana_style, with_roma)],
+    }
+}
+
+// DynamoDBから取得した住所情報(Map)を返却用の住所データに変換する
+// 読み仮名は指定された形式に変換し、ローマ字表記は要求された場合のみ設定する
+// 書き込みが途中の項目などで必須の項目がない場合は、空文字として返す
+fn attribute_to_address(
+    record: &HashMap<String, AttributeValue>,
+    kana_style: KanaStyle,
+    with_roma: bool,
+) -> Address {
+    let required = |name: &str| {
+        get_string(record, name).unwrap_or_else(|| {
+            tracing::warn!(attribute = name, "Missing address attribute");
+            "".to_string()
+        })
+    };
+
+    let prefecture = required("prefecture");
+    let city = required("city");
+    let town = required("town");
+    let prefecture_kana = required("prefecture_kana");
+    let city_kana = required("city_kana");
+    let town_kana = required("town_kana");
+    // 種別を持たない古い形式の項目は住所の郵便番号として扱う
+    let record_type = get_string(record, "record_type").unwrap_or_else(|| "town".to_string());
+
+    // 大口事業所個別番号であれば事業所の情報を取得
+    let business = if record_type == "business" {
+        Some(Business {
+            name: required("business_name"),
+            name_kana: kana_style.convert(&required("business_name_kana")),
+            address: required("business_address"),
+            handling_office: required("handling_office"),
+            is_post_office_box: get_bool(record, "is_post_office_box").unwrap_or(false),
+        })
+    } else {
+        None
+    };
+
+    // 町域名の注記があれば取得
+    let annotation = get_map(record, "annotation").map(attribute_to_annotation);
+
+    // ローマ字表記が要求されていれば取得
+    let roma = |name: &str| {
+        if with_roma {
+            get_string(record, name)
+        } else {
+            None
+        }
+    };
+
+    // 地割番号の一覧があれば取得
+    let chiwari = record
+        .get("chiwari")
+        .and_then(|chiwari| chiwari.as_l().ok())
+        .map(|chiwari| {
+            chiwari
+                .iter()
+                .filter_map(|number| number.as_n().ok()?.parse::<u32>().ok())
+                .collect::<Vec<_>>()
+        });
+
+    // 郡・市区町村・区に分けた市区町村名があれば取得
+    let city_name = get_map(record, "city_name").map(attribute_to_city_name);
+
+    // 更新の表示と変更理由は、記録される前に書き込まれた住所にはない
+    let changed = get_string(record, "changed");
+    let change_reason = record
+        .get("change_reason")
+        .and_then(|change_reason| change_reason.as_n().ok()?.parse::<u8>().ok());
+
+    Address {
+        record_type,
+        prefecture,
+        city,
+        town,
+        prefecture_kana: kana_style.convert(&prefecture_kana),
+        city_kana: kana_style.convert(&city_kana),
+        town_kana: kana_style.convert(&town_kana),
+        city_name,
+        prefecture_roma: roma("prefecture_roma"),
+        city_roma: roma("city_roma"),
+        town_roma: roma("town_roma"),
+        business,
+        annotation,
+        chiwari,
+        changed,
+        change_reason,
+    }
+}
+
+// DynamoDBから取得した市区町村名(Map)を返却用のデータに変換する
+fn attribute_to_city_name(city_name: &HashMap<String, AttributeValue>) -> CityName {
+    CityName {
+        county: get_string(city_name, "county"),
+        city: get_string(city_name, "city").unwrap_or_default(),
+        ward: get_string(city_name, "ward"),
+    }
+}
+
+// DynamoDBから取得した注記情報(Map)を返却用の注記データに変換する
+fn attribute_to_annotation(annotation: &HashMap<String, AttributeValue>) -> Annotation {
+    let to_string_list = |name: &str| match annotation.get(name).and_then(|value| value.as_l().ok())
+    {
+        Some(values) => values
+            .iter()
+            .filter_map(|value| value.as_s().ok().cloned())
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+
+    Annotation {
+        text: get_string(annotation, "text").unwrap_or_default(),
+        floor: get_string(annotation, "floor"),
+        chome: to_string_list("chome"),
+        banchi: to_string_list("banchi"),
+        excluded: get_bool(annotation, "excluded").unwrap_or(false),
+        others: to_string_list("others"),
+    }
+}
+
+// 文字列の属性を取得する (属性がない場合や型が異なる場合はNone)
+fn get_string(map: &HashMap<String, AttributeValue>, name: &str) -> Option<String> {
+    map.get(name)
+        .and_then(|value| value.as_s().ok())
+        .map(|value| value.to_string())
+}
+
+// 真偽値の属性を取得する (属性がない場合や型が異なる場合はNone)
+fn get_bool(map: &HashMap<String, AttributeValue>, name: &str) -> Option<bool> {
+    map.get(name)
+        .and_then(|value| value.as_bool().ok())
+        .copied()
+}
+
+// Mapの属性を取得する (属性がない場合や型が異なる場合はNone)
+fn get_map<'a>(
+    map: &'a HashMap<String, AttributeValue>,
+    name: &str,
+) -> Option<&'a HashMap<String, AttributeValue>> {
+    map.get(name).and_then(|value| value.as_m().ok())
+}
+
 fn postal_code_normalize(postal_code: &str) -> String {
     let hankaku: String = postal_code.chars().map(zenkaku_to_hankaku).collect();
 
@@ -131,3 +594,184 @@ async fn main() -> Result<(), Error> {
     }))
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(value: &str) -> AttributeValue {
+        return AttributeValue::S(value.to_string());
+    }
+
+    fn response_json(response: Response<Body>) -> serde_json::Value {
+        match response.body() {
+            Body::Text(text) => serde_json::from_str(text).unwrap(),
+            body => panic!("unexpected body: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn normalize_zenkaku_and_hyphen() {
+        assert_eq!(postal_code_normalize("060-0042"), "0600042");
+        assert_eq!(postal_code_normalize("０６０－００４２"), "0600042");
+        assert_eq!(postal_code_normalize("０６０-0042"), "0600042");
+    }
+
+    #[test]
+    fn parse_valid_parameters() {
+        assert_eq!(
+            parse_parameters(Some("０６０－００４２"), Some("hiragana"), Some("1")),
+            Ok(QueryParameters {
+                postal_code: "0600042".to_string(),
+                kana_style: KanaStyle::Hiragana,
+                with_roma: true,
+            })
+        );
+        assert_eq!(
+            parse_parameters(Some("0600042"), None, None),
+            Ok(QueryParameters {
+                postal_code: "0600042".to_string(),
+                kana_style: KanaStyle::Zenkaku,
+                with_roma: false,
+            })
+        );
+    }
+
+    #[test]
+    fn reject_invalid_parameters() {
+        let error =
+            |postal_code, kana, roma| parse_parameters(postal_code, kana, roma).unwrap_err().0;
+
+        assert_eq!(error(None, None, None), ErrorCode::MissingPostalCode);
+        assert_eq!(
+            error(Some("060004"), None, None),
+            ErrorCode::InvalidPostalCode
+        );
+        assert_eq!(
+            error(Some("060004a"), None, None),
+            ErrorCode::InvalidPostalCode
+        );
+        assert_eq!(
+            error(Some("0600042"), Some("katakana"), None),
+            ErrorCode::InvalidParameter
+        );
+        assert_eq!(
+            error(Some("0600042"), None, Some("yes")),
+            ErrorCode::InvalidParameter
+        );
+    }
+
+    #[test]
+    fn error_response_has_status_and_code() {
+        let response = error_response(
+            404,
+            ErrorCode::NotFound,
+            "postal code not found: 0000000".to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(response.status(), 404);
+        assert_eq!(
+            response_json(response),
+            serde_json::json!({
+                "error": "not_found",
+                "message": "postal code not found: 0000000",
+            })
+        );
+
+        let response = error_response(
+            400,
+            ErrorCode::InvalidParameter,
+            "roma must be true, false, 1 or 0: yes".to_string(),
+        )
+        .unwrap();
+        assert_eq!(response.status(), 400);
+        assert_eq!(response_json(response)["error"], "invalid_parameter");
+    }
+
+    #[test]
+    fn convert_town_address() {
+        let address = HashMap::from([
+            ("prefecture".to_string(), s("北海道")),
+            ("city".to_string(), s("札幌市中央区")),
+            ("town".to_string(), s("大通西")),
+            ("prefecture_kana".to_string(), s("ホッカイドウ")),
+            ("city_kana".to_string(), s("サッポロシチュウオウク")),
+            ("town_kana".to_string(), s("ｵｵﾄﾞｵﾘﾆｼ")),
+            ("town_roma".to_string(), s("ODORINISHI")),
+            (
+                "annotation".to_string(),
+                AttributeValue::M(HashMap::from([
+                    ("text".to_string(), s("１～１９丁目")),
+                    (
+                        "chome".to_string(),
+                        AttributeValue::L(vec![s("１～１９丁目")]),
+                    ),
+                    ("excluded".to_string(), AttributeValue::Bool(false)),
+                ])),
+            ),
+            (
+                "chiwari".to_string(),
+                AttributeValue::L(vec![AttributeValue::N("64".to_string())]),
+            ),
+            (
+                "change_reason".to_string(),
+                AttributeValue::N("0".to_string()),
+            ),
+        ]);
+
+        let converted = attribute_to_address(&address, KanaStyle::Hiragana, false);
+
+        assert_eq!(converted.record_type, "town");
+        assert_eq!(converted.town, "大通西");
+        assert_eq!(converted.town_kana, "おおどおりにし");
+        assert_eq!(converted.town_roma, None);
+        assert!(converted.business.is_none());
+        let annotation = converted.annotation.unwrap();
+        assert_eq!(annotation.chome, vec!["１～１９丁目"]);
+        assert!(annotation.banchi.is_empty());
+        assert_eq!(converted.chiwari, Some(vec![64]));
+        assert_eq!(converted.change_reason, Some(0));
+
+        let converted = attribute_to_address(&address, KanaStyle::Zenkaku, true);
+        assert_eq!(converted.town_kana, "オオドオリニシ");
+        assert_eq!(converted.town_roma.as_deref(), Some("ODORINISHI"));
+    }
+
+    #[test]
+    fn convert_business_address_with_missing_attributes() {
+        let address = HashMap::from([
+            ("record_type".to_string(), s("business")),
+            ("prefecture".to_string(), s("北海道")),
+            ("business_name".to_string(), s("札幌市役所")),
+            ("business_name_kana".to_string(), s("サツポロシヤクシヨ")),
+            (
+                "is_post_office_box".to_string(),
+                AttributeValue::Bool(false),
+            ),
+        ]);
+
+        let converted = attribute_to_address(&address, KanaStyle::Hankaku, false);
+
+        assert_eq!(converted.record_type, "business");
+        assert_eq!(converted.city, "");
+        assert_eq!(converted.town_kana, "");
+        let business = converted.business.unwrap();
+        assert_eq!(business.name, "札幌市役所");
+        assert_eq!(business.name_kana, "ｻﾂﾎﾟﾛｼﾔｸｼﾖ");
+        assert_eq!(business.address, "");
+    }
+
+    #[test]
+    fn item_without_addresses_is_one_address() {
+        let item = HashMap::from([
+            ("postal_code".to_string(), s("0600042")),
+            ("town".to_string(), s("大通西")),
+        ]);
+
+        let addresses = item_to_addresses(&item, KanaStyle::Zenkaku, false);
+
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].town, "大通西");
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::{AttributeValue, KeysAndAttributes};
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use tokio::task::JoinSet;
+
+use crate::batch_write::{backoff_delay, BatchWriteConfig};
+
+/// 郵便番号のリストに対応する項目をbatch_get_itemでまとめて取得する
+/// 分割したリクエストは、batch_write_itemと同じ同時実行数の上限までタスクとして並行して実行する
+pub async fn batch_get_postal_code_items(
+    client: &Client,
+    table_name: &str,
+    postal_codes: &[String],
+    config: BatchWriteConfig,
+) -> Result<HashMap<String, HashMap<String, AttributeValue>>, Error> {
+    let mut items = HashMap::<String, HashMap<String, AttributeValue>>::new();
+    let mut in_flight = JoinSet::<Result<Vec<HashMap<String, AttributeValue>>, Error>>::new();
+
+    // batch_get_itemは一度に100件までしか取得できないため分割して取得する
+    for chunk in postal_codes.chunks(100) {
+        // 同時実行数の上限に達していれば、実行中のリクエストが一つ完了するまで待つ
+        while in_flight.len() >= config.concurrency.max(1) {
+            if let Some(result) = in_flight.join_next().await {
+                insert_items(&mut items, result??);
+            }
+        }
+
+        let client = client.clone();
+        let table_name = table_name.to_string();
+        let chunk = chunk.to_vec();
+        in_flight.spawn(async move {
+            batch_get_chunk(&client, &table_name, &chunk, config.max_attempts).await
+        });
+    }
+    while let Some(result) = in_flight.join_next().await {
+        insert_items(&mut items, result??);
+    }
+
+    return Ok(items);
+}
+
+// 100件までの郵便番号に対応する項目を取得する
+// 未処理のキーは指数バックオフで再試行し、試行回数の上限に達しても残っていればエラーにする
+async fn batch_get_chunk(
+    client: &Client,
+    table_name: &str,
+    postal_codes: &[String],
+    max_attempts: usize,
+) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+    let mut items = Vec::<HashMap<String, AttributeValue>>::new();
+    let keys = postal_codes
+        .iter()
+        .map(|postal_code| {
+            HashMap::from([(
+                "postal_code".to_string(),
+                AttributeValue::S(postal_code.clone()),
+            )])
+        })
+        .collect::<Vec<_>>();
+    let mut request = KeysAndAttributes::builder().set_keys(Some(keys)).build();
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let output = client
+            .batch_get_item()
+            .request_items(table_name, request)
+            .send()
+            .await?;
+
+        if let Some(responses) = output.responses().and_then(|r| r.get(table_name)) {
+            items.extend(responses.iter().cloned());
+        }
+
+        // 未処理のキーが残っていれば、待機してから未処理のキーだけを再度取得する
+        match output.unprocessed_keys().and_then(|u| u.get(table_name)) {
+            Some(unprocessed) if unprocessed.keys().is_some_and(|k| !k.is_empty()) => {
+                request = unprocessed.clone();
+            }
+            _ => break,
+        }
+
+        let unprocessed_count = request.keys().map_or(0, |keys| keys.len());
+        if attempt >= max_attempts {
+            tracing::error!(
+                table_name = table_name,
+                unprocessed = unprocessed_count,
+                "batch_get_item gave up after max attempts"
+            );
+            //取得できなかった項目を新規として扱うと履歴やバージョンが正しく書き込まれないため、中断する
+            return Err(format!(
+                "batch_get_item left {} unprocessed keys after {} attempts",
+                unprocessed_count, attempt
+            )
+            .into());
+        }
+        tracing::warn!(
+            table_name = table_name,
+            attempt = attempt,
+            unprocessed = unprocessed_count,
+            "batch_get_item returned unprocessed keys"
+        );
+        tokio::time::sleep(backoff_delay(attempt)).await;
+    }
+
+    return Ok(items);
+}
+
+// 取得した項目を郵便番号をキーにして格納する
+fn insert_items(
+    items: &mut HashMap<String, HashMap<String, AttributeValue>>,
+    fetched: Vec<HashMap<String, AttributeValue>>,
+) {
+    for item in fetched {
+        let postal_code = item.get("postal_code").unwrap().as_s().unwrap().to_owned();
+        items.insert(postal_code, item);
+    }
+}
@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use aws_sdk_dynamodb::error::{ProvideErrorMetadata, SdkError};
+use aws_sdk_dynamodb::operation::batch_write_item::BatchWriteItemError;
+use aws_sdk_dynamodb::types::WriteRequest;
+use aws_sdk_dynamodb::Client;
+use lambda_runtime::Error;
+use rand::Rng;
+use tokio::task::JoinSet;
+
+// batch_write_itemで一度に送信できる最大件数
+const BATCH_SIZE: usize = 25;
+// 再試行時の待機時間の基準値と上限 (ミリ秒)
+const BASE_DELAY_MILLIS: u64 = 50;
+const MAX_DELAY_MILLIS: u64 = 5_000;
+// 再試行を含めた試行回数のデフォルト値
+pub const DEFAULT_MAX_ATTEMPTS: usize = 8;
+// 同時に実行するbatch_write_itemの数のデフォルト値
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// batch_write_itemの実行方法の設定
+#[derive(Debug, Clone, Copy)]
+pub struct BatchWriteConfig {
+    // 再試行を含めた試行回数の上限
+    pub max_attempts: usize,
+    // 同時に実行するbatch_write_itemの数の上限
+    pub concurrency: usize,
+}
+
+/// WriteRequestを溜めておき、一定数ごとにbatch_write_itemで書き込む
+/// バッチリクエストは設定された数までタスクとして並行して実行され、
+/// 呼び出し元が住所レコードの読み込みなど他の処理をしている間も送信や再試行の待機が進む
+pub struct BatchWriter {
+    client: Client,
+    table_name: String,
+    config: BatchWriteConfig,
+    requests: Vec<WriteRequest>,
+    // 実行中のバッチリクエスト (完了すると送信件数と書き込めなかった件数を返す)
+    in_flight: JoinSet<Result<(usize, usize), Error>>,
+    // 書き込みに成功した件数
+    pub count: usize,
+    // 再試行しても書き込めなかった件数
+    pub failed_count: usize,
+}
+
+impl BatchWriter {
+    pub fn new(client: &Client, table_name: String, config: BatchWriteConfig) -> BatchWriter {
+        BatchWriter {
+            //クライアントは内部で接続を共有しているため、複製してタスクに渡す
+            client: client.clone(),
+            table_name,
+            config,
+            requests: Vec::new(),
+            in_flight: JoinSet::new(),
+            count: 0,
+            failed_count: 0,
+        }
+    }
+
+    /// リクエストを追加する
+    /// 大きなデータを送信するとエラーになるため、一定数溜まったらバッチリクエストを実行する
+    pub async fn push(&mut self, request: WriteRequest) -> Result<(), Error> {
+        self.requests.push(request);
+
+        if self.requests.len() == BATCH_SIZE {
+            // 同時実行数の上限に達していれば、実行中のリクエストが一つ完了するまで待つ
+            while self.in_flight.len() >= self.config.concurrency.max(1) {
+                self.wait_one().await?;
+            }
+            self.start_batch();
+        }
+
+        Ok(())
+    }
+
+    /// 溜まっているリクエストをすべて書き込み、実行中のリクエストがすべて完了するまで待つ
+    /// flushが完了した時点で、それまでにpushしたリクエストはすべて処理済みになっている
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        if !self.requests.is_empty() {
+            self.start_batch();
+        }
+
+        while !self.in_flight.is_empty() {
+            self.wait_one().await?;
+        }
+
+        Ok(())
+    }
+
+    // 溜まっているリクエストのバッチリクエストをタスクとして開始する
+    fn start_batch(&mut self) {
+        let requests = std::mem::take(&mut self.requests);
+        let client = self.client.clone();
+        let table_name = self.table_name.clone();
+        let max_attempts = self.config.max_attempts;
+
+        self.in_flight.spawn(async move {
+            let request_count = requests.len();
+            let failed_count =
+                send_batch_write_item(&client, table_name, requests, max_attempts).await?;
+
+            Ok((request_count, failed_count))
+        });
+    }
+
+    // 実行中のバッチリクエストが一つ完了するまで待ち、結果を集計する
+    async fn wait_one(&mut self) -> Result<(), Error> {
+        if let Some(result) = self.in_flight.join_next().await {
+            let (request_count, failed_count) = result??;
+
+            self.count += request_count - failed_count;
+            self.failed_count += failed_count;
+        }
+
+        Ok(())
+    }
+}
+
+/// batch_write_itemのリクエストを送信する
+/// 未処理の項目とスロットリングによるエラーは指数バックオフで再試行し、
+/// 試行回数の上限に達しても書き込めなかった件数を返す
+async fn send_batch_write_item(
+    client: &Client,
+    table_name: String,
+    requests: Vec<WriteRequest>,
+    max_attempts: usize,
+) -> Result<usize, Error> {
+    let mut requests = requests;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        match client
+            .batch_write_item()
+            .request_items(table_name.clone(), requests.clone())
+            .send()
+            .await
+        {
+            Ok(output) => {
+                //未処理の項目が残っていれば、その項目だけを再試行する
+                requests = output
+                    .unprocessed_items()
+                    .and_then(|items| items.get(&table_name))
+                    .cloned()
+                    .unwrap_or_default();
+                if requests.is_empty() {
+                    return Ok(0);
+                }
+                tracing::warn!(
+                    table_name = table_name,
+                    attempt = attempt,
+                    unprocessed = requests.len(),
+                    "batch_write_item returned unprocessed items"
+                );
+            }
+            Err(err) if is_throttling_error(&err) => {
+                tracing::warn!(
+                    table_name = table_name,
+                    attempt = attempt,
+                    error = %err,
+                    "batch_write_item was throttled"
+                );
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        if attempt >= max_attempts {
+            tracing::error!(
+                table_name = table_name,
+                failed = requests.len(),
+                "batch_write_item gave up after max attempts"
+            );
+            return Ok(requests.len());
+        }
+
+        tokio::time::sleep(backoff_delay(attempt)).await;
+    }
+}
+
+// スロットリングによるエラーか判定する
+fn is_throttling_error(err: &SdkError<BatchWriteItemError>) -> bool {
+    match err {
+        SdkError::ServiceError(service_error) => match service_error.err() {
+            BatchWriteItemError::ProvisionedThroughputExceededException(_)
+            | BatchWriteItemError::RequestLimitExceeded(_) => true,
+            other => other.code() == Some("ThrottlingException"),
+        },
+        _ => false,
+    }
+}
+
+/// 試行回数に応じた待機時間を計算する
+/// 待機時間は指数的に延ばして上限で打ち止めにし、同時に再試行が集中しないようにフルジッターを掛ける
+pub fn backoff_delay(attempt: usize) -> Duration {
+    let max_delay = BASE_DELAY_MILLIS
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_DELAY_MILLIS);
+
+    Duration::from_millis(rand::thread_rng().gen_range(0..=max_delay))
+}
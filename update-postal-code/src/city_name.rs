@@ -0,0 +1,96 @@
+use crate::postal_code_record::CityName;
+
+/// 市区町村名を郡・市区町村・政令指定都市の区に分ける
+/// ken_allの市区町村名は「横浜市中区」「余市郡仁木町」のように連結されているため、全国地方公共団体コードから種別を判定して分割する
+/// 例: 「横浜市中区」(14104) → 市「横浜市」区「中区」、「余市郡仁木町」(01409) → 郡「余市郡」町村「仁木町」
+pub fn split_city_name(national_local_government_code: &str, city: &str) -> CityName {
+    let whole = || CityName {
+        county: None,
+        city: city.to_string(),
+        ward: None,
+    };
+
+    let code = national_local_government_code.as_bytes();
+    if code.len() != 5 || !code.iter().all(|c| c.is_ascii_digit()) {
+        return whole();
+    }
+
+    match code[2] {
+        // 下3桁が1xxは政令指定都市とその区 (東京都の特別区は区そのものが市区町村なので分割しない)
+        b'1' if &code[..2] != b"13" => {
+            //政令指定都市の名前には「市」が途中に含まれないため、最初の「市」までを市名とする
+            if let Some(index) = city.find('市') {
+                let (designated_city, ward) = city.split_at(index + '市'.len_utf8());
+                if ward.ends_with('区') {
+                    return CityName {
+                        county: None,
+                        city: designated_city.to_string(),
+                        ward: Some(ward.to_string()),
+                    };
+                }
+            }
+        }
+        // 下3桁が3xx以上は郡に属する町村 (東京都の島しょ部の町村のように郡に属さないものもある)
+        b'3'..=b'9' => {
+            //「余市郡」のように郡名に「市」を含むことがあるため、「郡」で分割する
+            if let Some(index) = city.find('郡') {
+                let (county, town) = city.split_at(index + '郡'.len_utf8());
+                if !town.is_empty() {
+                    return CityName {
+                        county: Some(county.to_string()),
+                        city: town.to_string(),
+                        ward: None,
+                    };
+                }
+            }
+        }
+        _ => {}
+    }
+
+    return whole();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city_name(county: Option<&str>, city: &str, ward: Option<&str>) -> CityName {
+        return CityName {
+            county: county.map(str::to_string),
+            city: city.to_string(),
+            ward: ward.map(str::to_string),
+        };
+    }
+
+    #[test]
+    fn split_designated_city_ward() {
+        assert_eq!(
+            split_city_name("14104", "横浜市中区"),
+            city_name(None, "横浜市", Some("中区"))
+        );
+    }
+
+    #[test]
+    fn keep_tokyo_special_ward() {
+        assert_eq!(
+            split_city_name("13101", "千代田区"),
+            city_name(None, "千代田区", None)
+        );
+    }
+
+    #[test]
+    fn split_county_containing_city_character() {
+        assert_eq!(
+            split_city_name("01409", "余市郡仁木町"),
+            city_name(Some("余市郡"), "仁木町", None)
+        );
+    }
+
+    #[test]
+    fn keep_island_town_without_county() {
+        assert_eq!(
+            split_city_name("13361", "大島町"),
+            city_name(None, "大島町", None)
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use std::io::Read;
+
+use crate::error::{csv_record, row_number, IngestError};
+
+/// 日本郵便が公開しているCSVファイルの形式の定義
+/// ヘッダー行の有無や列の位置などをファイルの形式ごとに定義し、すべてのパーサーで同じ方法で読み込む
+#[derive(Debug, Clone, Copy)]
+pub struct CsvSchema<C> {
+    /// エラーメッセージなどに使用するファイルの名前
+    pub file: &'static str,
+    /// 先頭行がヘッダー行であればtrue (日本郵便のファイルにはヘッダー行がない)
+    pub has_headers: bool,
+    /// 列の区切り文字
+    pub delimiter: u8,
+    /// 「"」で囲まれた値を引用符付きの値として扱う場合はtrue
+    pub quoting: bool,
+    /// 一行に含まれる列の数 (これより列が少ない行は行の形式の違反として返す)
+    pub column_count: usize,
+    /// 各項目の列の位置 (0始まり)
+    pub columns: C,
+}
+
+impl<C> CsvSchema<C> {
+    /// 定義に従ってCSVを読み込み、一行ずつ返すイテレーターを作成する
+    pub fn records<R: Read>(&self, reader: R) -> CsvRecords<R> {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(self.has_headers)
+            .delimiter(self.delimiter)
+            .quoting(self.quoting)
+            //列の数が異なる行も読み込み、列が足りない行は行番号付きの形式の違反として返す
+            .flexible(true)
+            .from_reader(reader);
+
+        CsvRecords {
+            file: self.file,
+            column_count: self.column_count,
+            records: reader.into_records(),
+        }
+    }
+}
+
+/// CSVの行を順に返すイテレーター
+/// 読み込めなかった行や列の数が足りない行は、行番号付きのエラーとして返す
+pub struct CsvRecords<R> {
+    file: &'static str,
+    column_count: usize,
+    records: csv::StringRecordsIntoIter<R>,
+}
+
+impl<R: Read> Iterator for CsvRecords<R> {
+    type Item = Result<csv::StringRecord, IngestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match csv_record(self.file, self.records.next()?) {
+            Ok(record) => record,
+            Err(err) => return Some(Err(err)),
+        };
+
+        //レコードの長さが不正であればエラー
+        if record.len() < self.column_count {
+            return Some(Err(IngestError::InvalidRow {
+                file: self.file,
+                row: row_number(&record),
+                message: format!(
+                    "expected {} columns but found {}",
+                    self.column_count,
+                    record.len()
+                ),
+            }));
+        }
+
+        return Some(Ok(record));
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, HashMap};
+
+use aws_sdk_dynamodb::types::AttributeValue;
+
+/// ドライラン時に返却する、更新予定の差分のレポート
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Default)]
+pub struct DryRunReport {
+    pub added_count: usize,
+    pub modified_count: usize,
+    pub deleted_count: usize,
+    // 削除件数が上限を超えているため、実際の更新では中止される場合にtrue
+    pub delete_ratio_exceeded: bool,
+    // national_local_government_codeごとの差分
+    pub municipalities: BTreeMap<String, MunicipalityDiff>,
+}
+
+/// national_local_government_code単位の差分
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Default)]
+pub struct MunicipalityDiff {
+    pub added: Vec<String>,
+    pub modified: Vec<ModifiedPostalCode>,
+    pub deleted: Vec<String>,
+}
+
+/// 内容が変更される郵便番号
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+pub struct ModifiedPostalCode {
+    pub postal_code: String,
+    pub changes: Vec<FieldChange>,
+}
+
+/// 変更されるフィールドの変更前と変更後の値
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+pub struct FieldChange {
+    // addresses[0].townのような形式のフィールド名
+    pub field: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl DryRunReport {
+    /// 書き込み予定の項目と現在の項目を比較して、追加か変更として記録する
+    /// 内容が同じであれば何も記録しない
+    pub fn add_put(
+        &mut self,
+        national_local_government_code: &str,
+        postal_code: &str,
+        current: Option<&HashMap<String, AttributeValue>>,
+        planned: &HashMap<String, AttributeValue>,
+    ) {
+        match current {
+            None => {
+                self.added_count += 1;
+                self.municipality(national_local_government_code)
+                    .added
+                    .push(postal_code.to_string());
+            }
+            Some(current) => {
+                let changes = diff_fields(current, planned);
+                if !changes.is_empty() {
+                    self.modified_count += 1;
+                    self.municipality(national_local_government_code)
+                        .modified
+                        .push(ModifiedPostalCode {
+                            postal_code: postal_code.to_string(),
+                            changes,
+                        });
+                }
+            }
+        }
+    }
+
+    /// 削除予定の郵便番号を記録する
+    pub fn add_delete(&mut self, national_local_government_code: &str, postal_code: &str) {
+        self.deleted_count += 1;
+        self.municipality(national_local_government_code)
+            .deleted
+            .push(postal_code.to_string());
+    }
+
+    fn municipality(&mut self, national_local_government_code: &str) -> &mut MunicipalityDiff {
+        self.municipalities
+            .entry(national_local_government_code.to_string())
+            .or_default()
+    }
+}
+
+/// 二つの項目をフィールド単位で比較して、異なるフィールドの一覧を返す
+fn diff_fields(
+    before: &HashMap<String, AttributeValue>,
+    after: &HashMap<String, AttributeValue>,
+) -> Vec<FieldChange> {
+    let mut before_fields = BTreeMap::<String, String>::new();
+    let mut after_fields = BTreeMap::<String, String>::new();
+    for (name, value) in before {
+        flatten_attribute(name, value, &mut before_fields);
+    }
+    for (name, value) in after {
+        flatten_attribute(name, value, &mut after_fields);
+    }
+
+    let mut field_names = before_fields.keys().cloned().collect::<Vec<_>>();
+    for name in after_fields.keys() {
+        if !before_fields.contains_key(name) {
+            field_names.push(name.clone());
+        }
+    }
+    field_names.sort();
+
+    let mut changes = Vec::<FieldChange>::new();
+    for field in field_names {
+        let before = before_fields.remove(&field);
+        let after = after_fields.remove(&field);
+        if before != after {
+            changes.push(FieldChange {
+                field,
+                before,
+                after,
+            });
+        }
+    }
+
+    return changes;
+}
+
+/// 入れ子になった属性値を「addresses[0].town」のようなフィールド名と文字列のペアに展開する
+fn flatten_attribute(name: &str, value: &AttributeValue, fields: &mut BTreeMap<String, String>) {
+    match value {
+        AttributeValue::M(map) => {
+            for (key, value) in map {
+                flatten_attribute(&format!("{}.{}", name, key), value, fields);
+            }
+        }
+        AttributeValue::L(list) => {
+            for (index, value) in list.iter().enumerate() {
+                flatten_attribute(&format!("{}[{}]", name, index), value, fields);
+            }
+        }
+        AttributeValue::S(value) | AttributeValue::N(value) => {
+            fields.insert(name.to_string(), value.clone());
+        }
+        AttributeValue::Bool(value) => {
+            fields.insert(name.to_string(), value.to_string());
+        }
+        AttributeValue::Ss(values) | AttributeValue::Ns(values) => {
+            fields.insert(name.to_string(), values.join(","));
+        }
+        _ => {
+            fields.insert(name.to_string(), format!("{:?}", value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(value: &str) -> AttributeValue {
+        return AttributeValue::S(value.to_string());
+    }
+
+    fn item(town: &str, is_post_office_box: bool) -> HashMap<String, AttributeValue> {
+        return HashMap::from([
+            ("postal_code".to_string(), s("0600000")),
+            (
+                "addresses".to_string(),
+                AttributeValue::L(vec![AttributeValue::M(HashMap::from([
+                    ("town".to_string(), s(town)),
+                    (
+                        "is_post_office_box".to_string(),
+                        AttributeValue::Bool(is_post_office_box),
+                    ),
+                    ("chiwari".to_string(), AttributeValue::L(vec![])),
+                ]))]),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn flatten_nested_attributes() {
+        let mut fields = BTreeMap::<String, String>::new();
+        for (name, value) in &item("大通西", true) {
+            flatten_attribute(name, value, &mut fields);
+        }
+
+        assert_eq!(
+            fields,
+            BTreeMap::from([
+                (
+                    "addresses[0].is_post_office_box".to_string(),
+                    "true".to_string()
+                ),
+                ("addresses[0].town".to_string(), "大通西".to_string()),
+                ("postal_code".to_string(), "0600000".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn diff_changed_fields_only() {
+        let changes = diff_fields(&item("大通西", false), &item("大通東", false));
+
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "addresses[0].town");
+        assert_eq!(changes[0].before.as_deref(), Some("大通西"));
+        assert_eq!(changes[0].after.as_deref(), Some("大通東"));
+    }
+
+    #[test]
+    fn diff_added_and_removed_fields() {
+        let before = item("大通西", false);
+        let mut after = before.clone();
+        after.remove("addresses");
+        after.insert("dataset_version".to_string(), s("2023-02-01"));
+
+        let changes = diff_fields(&before, &after);
+        let fields = changes
+            .iter()
+            .map(|change| {
+                (
+                    change.field.as_str(),
+                    change.before.as_deref(),
+                    change.after.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            fields,
+            vec![
+                ("addresses[0].is_post_office_box", Some("false"), None),
+                ("addresses[0].town", Some("大通西"), None),
+                ("dataset_version", None, Some("2023-02-01")),
+            ]
+        );
+    }
+
+    #[test]
+    fn same_items_have_no_changes() {
+        assert!(diff_fields(&item("大通西", true), &item("大通西", true)).is_empty());
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+
+/// 郵便番号データの取り込み処理で発生するエラー
+#[derive(Debug)]
+pub enum IngestError {
+    /// ダウンロードのリクエストに失敗した
+    Download { url: String, source: reqwest::Error },
+    /// ダウンロード時に想定外のHTTPステータスが返された
+    HttpStatus { url: String, status: u16 },
+    /// ローカルファイルや標準入力の読み込みに失敗した
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    /// zipファイルの展開に失敗した
+    Zip(zip::result::ZipError),
+    /// ファイルの内容をUTF-8としてもShift-JISとしても解釈できなかった
+    Encoding,
+    /// CSVの行を読み込めなかった
+    Csv {
+        file: &'static str,
+        row: Option<u64>,
+        source: csv::Error,
+    },
+    /// CSVの行の内容が不正 (行の形式の検証ルールで許容件数まで読み飛ばす)
+    InvalidRow {
+        file: &'static str,
+        row: u64,
+        message: String,
+    },
+    /// ken_allが全国地方公共団体コードの順に並んでいない
+    NotSorted {
+        file: &'static str,
+        row: u64,
+        national_local_government_code: String,
+    },
+    /// 住所レコードの検証で、違反の件数が許容件数を超えたルールがあった
+    Validation { rules: Vec<String> },
+    /// DynamoDBに保存されている項目の形式が不正
+    InvalidItem { key: String, message: String },
+    /// 環境変数の設定値が不正
+    Config { name: String, value: String },
+    /// 削除される郵便番号の割合が上限を超えた
+    DeleteRatioExceeded {
+        deleted: usize,
+        previous: usize,
+        max_ratio: f64,
+    },
+}
+
+impl IngestError {
+    /// エラーの種類を表す文字列
+    pub fn kind(&self) -> &'static str {
+        match self {
+            IngestError::Download { .. } => "download",
+            IngestError::HttpStatus { .. } => "http_status",
+            IngestError::Io { .. } => "io",
+            IngestError::Zip(_) => "zip",
+            IngestError::Encoding => "encoding",
+            IngestError::Csv { .. } => "csv",
+            IngestError::InvalidRow { .. } => "invalid_row",
+            IngestError::NotSorted { .. } => "not_sorted",
+            IngestError::Validation { .. } => "validation",
+            IngestError::InvalidItem { .. } => "invalid_item",
+            IngestError::Config { .. } => "config",
+            IngestError::DeleteRatioExceeded { .. } => "delete_ratio_exceeded",
+        }
+    }
+
+    /// エラーが発生したファイルの名前 (CSVの行のエラーの場合のみ)
+    pub fn file(&self) -> Option<&'static str> {
+        match self {
+            IngestError::Csv { file, .. }
+            | IngestError::InvalidRow { file, .. }
+            | IngestError::NotSorted { file, .. } => Some(file),
+            _ => None,
+        }
+    }
+
+    /// エラーが発生したCSVの行番号 (CSVの行のエラーの場合のみ)
+    pub fn row(&self) -> Option<u64> {
+        match self {
+            IngestError::Csv { row, .. } => *row,
+            IngestError::InvalidRow { row, .. } | IngestError::NotSorted { row, .. } => Some(*row),
+            _ => None,
+        }
+    }
+
+    /// 一行だけの不正で、その行を読み飛ばして読み込みを続けられるエラーであればtrue
+    pub fn is_row_error(&self) -> bool {
+        matches!(self, IngestError::InvalidRow { .. })
+    }
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Download { url, source } => {
+                write!(f, "failed to download {}: {}", url, source)
+            }
+            IngestError::HttpStatus { url, status } => {
+                write!(f, "unexpected HTTP status {} from {}", status, url)
+            }
+            IngestError::Io { path, source } => write!(f, "failed to read {}: {}", path, source),
+            IngestError::Zip(source) => write!(f, "failed to extract the zip file: {}", source),
+            IngestError::Encoding => write!(f, "contents are neither UTF-8 nor Shift-JIS"),
+            IngestError::Csv { file, row, source } => match row {
+                Some(row) => write!(f, "failed to read {} row {}: {}", file, row, source),
+                None => write!(f, "failed to read {}: {}", file, source),
+            },
+            IngestError::InvalidRow { file, row, message } => {
+                write!(f, "invalid {} row {}: {}", file, row, message)
+            }
+            IngestError::NotSorted {
+                file,
+                row,
+                national_local_government_code,
+            } => write!(
+                f,
+                "{} is not sorted by national_local_government_code at row {}: {}",
+                file, row, national_local_government_code
+            ),
+            IngestError::Validation { rules } => {
+                write!(f, "validation failed: {}", rules.join(", "))
+            }
+            IngestError::InvalidItem { key, message } => {
+                write!(f, "invalid stored item {}: {}", key, message)
+            }
+            IngestError::Config { name, value } => write!(f, "{} is invalid: {}", name, value),
+            IngestError::DeleteRatioExceeded {
+                deleted,
+                previous,
+                max_ratio,
+            } => write!(
+                f,
+                "too many postal codes would be deleted: {} of {} (max ratio {})",
+                deleted, previous, max_ratio
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            IngestError::Download { source, .. } => Some(source),
+            IngestError::Io { source, .. } => Some(source),
+            IngestError::Zip(source) => Some(source),
+            IngestError::Csv { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<zip::result::ZipError> for IngestError {
+    fn from(source: zip::result::ZipError) -> Self {
+        IngestError::Zip(source)
+    }
+}
+
+/// CSVの行の読み込み結果を、行番号付きのエラーに変換する
+/// 文字コードの変換に失敗した場合は、Encodingのエラーを返す
+pub fn csv_record(
+    file: &'static str,
+    result: Result<csv::StringRecord, csv::Error>,
+) -> Result<csv::StringRecord, IngestError> {
+    result.map_err(|source| {
+        //文字コードの変換に失敗した場合は、リーダーがIngestError::Encodingを包んだエラーを返している
+        if let csv::ErrorKind::Io(err) = source.kind() {
+            let inner = err
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<IngestError>());
+            if let Some(IngestError::Encoding) = inner {
+                return IngestError::Encoding;
+            }
+        }
+
+        IngestError::Csv {
+            file,
+            row: source.position().map(|position| position.line()),
+            source,
+        }
+    })
+}
+
+/// CSVの行番号を取得する
+pub fn row_number(record: &csv::StringRecord) -> u64 {
+    record
+        .position()
+        .map(|position| position.line())
+        .unwrap_or(0)
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::{AttributeValue, PutRequest, WriteRequest};
+
+use crate::error::IngestError;
+
+// 住所が変更されたかの判定に使用する住所情報の項目
+// 町域名から取り出した注記と地割番号は住所の内容そのものなので比較する
+// 更新の表示・変更理由やローマ字・分割した市区町村名のように住所から導出される項目は、
+// 住所が同じでもデータセットや保存形式によって値が変わるため比較しない
+const ADDRESS_ATTRIBUTES: [&str; 14] = [
+    "record_type",
+    "prefecture",
+    "prefecture_kana",
+    "city",
+    "city_kana",
+    "town",
+    "town_kana",
+    "business_name",
+    "business_name_kana",
+    "business_address",
+    "handling_office",
+    "is_post_office_box",
+    "annotation",
+    "chiwari",
+];
+
+/// UNIXエポックからのミリ秒をデータセットのバージョンに使用する日付(YYYY-MM-DD、UTC)に変換する
+pub fn dataset_version_from_millis(millis: u64) -> String {
+    // 1970-01-01からの日数を西暦の年月日に変換する
+    let days = (millis / 1000 / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!("{:04}-{:02}-{:02}", year, month, day);
+}
+
+/// UNIXエポックからのミリ秒を履歴のキーに使用する日時(YYYY-MM-DDTHH:MM:SS.sssZ、UTC)に変換する
+pub fn timestamp_from_millis(millis: u64) -> String {
+    let seconds_of_day = millis / 1000 % 86400;
+    return format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        dataset_version_from_millis(millis),
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        millis % 1000
+    );
+}
+
+/// 更新の実行ごとのバージョン
+#[derive(Debug, Clone)]
+pub struct UpdateVersion {
+    /// 住所が変更された項目に記録するデータセットのバージョン
+    pub dataset_version: String,
+    /// 履歴のキーに使用する更新の実行日時
+    /// 同じデータセットのバージョンで複数回実行しても、履歴が上書きされないようにする
+    pub superseded_at: String,
+}
+
+/// 書き込む項目に、住所が最後に変更されたデータセットのバージョンを設定する
+/// 既存の項目の住所が変更される場合は、変更前の住所を履歴テーブルに書き込むリクエストを返す
+pub fn apply_dataset_version(
+    item: &mut HashMap<String, AttributeValue>,
+    current: Option<&HashMap<String, AttributeValue>>,
+    version: &UpdateVersion,
+) -> Result<Option<WriteRequest>, IngestError> {
+    let planned_addresses = crate::item_to_address_list(item)?;
+    let dataset_version = &version.dataset_version;
+    let previous_version = current
+        .and_then(|current| current.get("dataset_version"))
+        .and_then(|version| version.as_s().ok());
+    let (dataset_version, history) = match (current, previous_version) {
+        // 住所が変わっていなければ、前回住所が変更されたバージョンを引き継ぐ
+        (Some(current), Some(previous_version)) => {
+            if is_address_changed(&crate::item_to_address_list(current)?, &planned_addresses) {
+                (
+                    dataset_version.to_string(),
+                    Some(history_request(current, version)?),
+                )
+            } else {
+                (previous_version.clone(), None)
+            }
+        }
+        // バージョンを保存する前に書き込まれた項目は、カナが半角カタカナで保存されているなど形式が異なり
+        // 住所を比較できないため、履歴を残さずに今回のバージョンを設定する
+        (Some(_), None) | (None, _) => (dataset_version.to_string(), None),
+    };
+
+    item.insert(
+        "dataset_version".to_string(),
+        AttributeValue::S(dataset_version),
+    );
+    return Ok(history);
+}
+
+/// 置き換えられる(または削除される)項目の住所を、履歴テーブルに書き込むリクエストを作成する
+/// 履歴は郵便番号と置き換えられた更新の実行日時をキーにして保存し、
+/// 置き換えたデータセットのバージョンは住所が有効だった期限として保存する
+pub fn history_request(
+    current: &HashMap<String, AttributeValue>,
+    version: &UpdateVersion,
+) -> Result<WriteRequest, IngestError> {
+    let postal_code = current
+        .get("postal_code")
+        .ok_or_else(|| IngestError::InvalidItem {
+            key: "".to_string(),
+            message: "postal_code is missing".to_string(),
+        })?;
+    let mut put_request = PutRequest::builder()
+        .item("postal_code", postal_code.clone())
+        .item(
+            "superseded_at",
+            AttributeValue::S(version.superseded_at.clone()),
+        )
+        .item(
+            "valid_until",
+            AttributeValue::S(version.dataset_version.clone()),
+        )
+        .item(
+            "addresses",
+            AttributeValue::L(crate::item_to_address_list(current)?),
+        );
+    // 住所が有効になったバージョンは、バージョンを保存する前に書き込まれた項目にはない
+    if let Some(valid_from) = current.get("dataset_version") {
+        put_request = put_request.item("valid_from", valid_from.clone());
+    }
+
+    return Ok(WriteRequest::builder()
+        .put_request(put_request.build())
+        .build());
+}
+
+/// 住所の項目のみを比較して、二つの住所情報(Map)が同じ住所を表しているか判定する
+pub fn is_same_address_attribute(a: &AttributeValue, b: &AttributeValue) -> bool {
+    return address_attributes(a) == address_attributes(b);
+}
+
+// 住所の項目のみを比較して、住所情報が変わったか判定する
+fn is_address_changed(current: &[AttributeValue], planned: &[AttributeValue]) -> bool {
+    return current.len() != planned.len()
+        || current
+            .iter()
+            .zip(planned)
+            .any(|(current, planned)| !is_same_address_attribute(current, planned));
+}
+
+// 住所情報から住所の項目の値を取り出す
+fn address_attributes(address: &AttributeValue) -> Option<Vec<Option<&AttributeValue>>> {
+    match address {
+        AttributeValue::M(address) => Some(
+            ADDRESS_ATTRIBUTES
+                .iter()
+                .map(|name| match *name {
+                    // 注記は解析結果ではなく、町域名から取り出した文字列で比較する
+                    "annotation" => address
+                        .get(*name)
+                        .and_then(|annotation| annotation.as_m().ok())
+                        .and_then(|annotation| annotation.get("text")),
+                    _ => address.get(*name),
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(addresses: Vec<HashMap<String, AttributeValue>>) -> HashMap<String, AttributeValue> {
+        return HashMap::from([
+            (
+                "postal_code".to_string(),
+                AttributeValue::S("0600000".to_string()),
+            ),
+            (
+                "dataset_version".to_string(),
+                AttributeValue::S("2023-01-01".to_string()),
+            ),
+            (
+                "addresses".to_string(),
+                AttributeValue::L(addresses.into_iter().map(AttributeValue::M).collect()),
+            ),
+        ]);
+    }
+
+    fn version() -> UpdateVersion {
+        return UpdateVersion {
+            dataset_version: "2023-02-01".to_string(),
+            superseded_at: "2023-02-01T00:00:00.000Z".to_string(),
+        };
+    }
+
+    fn address(town: &str) -> HashMap<String, AttributeValue> {
+        return HashMap::from([
+            (
+                "city".to_string(),
+                AttributeValue::S("札幌市中央区".to_string()),
+            ),
+            ("town".to_string(), AttributeValue::S(town.to_string())),
+        ]);
+    }
+
+    #[test]
+    fn derived_attributes_do_not_change_address() {
+        let current = item(vec![address("大通西")]);
+        let mut planned_address = address("大通西");
+        planned_address.insert(
+            "city_name".to_string(),
+            AttributeValue::M(HashMap::from([(
+                "city".to_string(),
+                AttributeValue::S("札幌市".to_string()),
+            )])),
+        );
+        planned_address.insert(
+            "changed".to_string(),
+            AttributeValue::S("changed".to_string()),
+        );
+        let mut planned = item(vec![planned_address]);
+
+        let history = apply_dataset_version(&mut planned, Some(&current), &version()).unwrap();
+
+        assert!(history.is_none());
+        assert_eq!(
+            planned.get("dataset_version"),
+            Some(&AttributeValue::S("2023-01-01".to_string()))
+        );
+    }
+
+    #[test]
+    fn changed_town_writes_history() {
+        let current = item(vec![address("大通西")]);
+        let mut planned = item(vec![address("大通東")]);
+
+        let history = apply_dataset_version(&mut planned, Some(&current), &version()).unwrap();
+
+        assert!(history.is_some());
+        assert_eq!(
+            planned.get("dataset_version"),
+            Some(&AttributeValue::S("2023-02-01".to_string()))
+        );
+    }
+
+    #[test]
+    fn item_without_dataset_version_is_migrated_without_history() {
+        let mut current = item(vec![address("大通西")]);
+        current.remove("dataset_version");
+        current.insert(
+            "town_kana".to_string(),
+            AttributeValue::S("ｵｵﾄﾞｵﾘﾆｼ".to_string()),
+        );
+        let mut planned_address = address("大通西");
+        planned_address.insert(
+            "town_kana".to_string(),
+            AttributeValue::S("オオドオリニシ".to_string()),
+        );
+        let mut planned = item(vec![planned_address]);
+
+        let history = apply_dataset_version(&mut planned, Some(&current), &version()).unwrap();
+
+        assert!(history.is_none());
+        assert_eq!(
+            planned.get("dataset_version"),
+            Some(&AttributeValue::S("2023-02-01".to_string()))
+        );
+    }
+
+    #[test]
+    fn malformed_current_item_is_error() {
+        let mut current = item(vec![address("大通西")]);
+        current.insert(
+            "addresses".to_string(),
+            AttributeValue::S("大通西".to_string()),
+        );
+        let mut planned = item(vec![address("大通西")]);
+
+        let err = apply_dataset_version(&mut planned, Some(&current), &version()).unwrap_err();
+
+        assert_eq!(err.kind(), "invalid_item");
+        assert_eq!(
+            err.to_string(),
+            "invalid stored item 0600000: addresses is not a list"
+        );
+    }
+
+    #[test]
+    fn changed_annotation_writes_history() {
+        let annotation = |text: &str, chome: &str| {
+            AttributeValue::M(HashMap::from([
+                ("text".to_string(), AttributeValue::S(text.to_string())),
+                (
+                    "chome".to_string(),
+                    AttributeValue::L(vec![AttributeValue::S(chome.to_string())]),
+                ),
+            ]))
+        };
+        let mut current_address = address("大通西");
+        current_address.insert(
+            "annotation".to_string(),
+            annotation("（１～１９丁目）", "1"),
+        );
+        let mut planned_address = address("大通西");
+        planned_address.insert(
+            "annotation".to_string(),
+            annotation("（１～２０丁目）", "1"),
+        );
+        let current = item(vec![current_address]);
+        let mut planned = item(vec![planned_address]);
+
+        let history = apply_dataset_version(&mut planned, Some(&current), &version()).unwrap();
+
+        assert!(history.is_some());
+        assert_eq!(
+            planned.get("dataset_version"),
+            Some(&AttributeValue::S("2023-02-01".to_string()))
+        );
+    }
+
+    #[test]
+    fn same_day_runs_have_different_history_keys() {
+        let first = timestamp_from_millis(1675209600000);
+        let second = timestamp_from_millis(1675245845123);
+
+        assert_eq!(first, "2023-02-01T00:00:00.000Z");
+        assert_eq!(second, "2023-02-01T10:04:05.123Z");
+        assert_ne!(first, second);
+    }
+}
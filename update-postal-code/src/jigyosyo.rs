@@ -0,0 +1,173 @@
+use std::io::Read;
+
+use crate::csv_schema::CsvSchema;
+use crate::error::{row_number, IngestError};
+use crate::kana;
+use crate::postal_code_record::{self, BusinessInfo, PostalCodeRecord};
+
+/// 大口事業所個別番号データのURL
+pub const JIGYOSYO_URL: &str = "https://www.post.japanpost.jp/zipcode/dl/jigyosyo/zip/jigyosyo.zip";
+
+/// jigyosyoの各項目の列の位置
+#[derive(Debug, Clone, Copy)]
+pub struct JigyosyoColumns {
+    pub national_local_government_code: usize,
+    pub name_kana: usize,
+    pub name: usize,
+    pub prefecture: usize,
+    pub city: usize,
+    pub town: usize,
+    pub address: usize,
+    pub postal_code: usize,
+    pub handling_office: usize,
+    pub is_post_office_box: usize,
+    pub is_change: usize,
+}
+
+/// jigyosyo.zipの形式 (ヘッダー行はない)
+pub const JIGYOSYO_SCHEMA: CsvSchema<JigyosyoColumns> = CsvSchema {
+    file: "jigyosyo",
+    has_headers: false,
+    delimiter: b',',
+    quoting: true,
+    column_count: 13,
+    columns: JigyosyoColumns {
+        national_local_government_code: 0,
+        name_kana: 1,
+        name: 2,
+        prefecture: 3,
+        city: 4,
+        town: 5,
+        address: 6,
+        postal_code: 7,
+        handling_office: 9,
+        is_post_office_box: 10,
+        is_change: 12,
+    },
+};
+
+/// 大口事業所個別番号データのCSVを読み込んで住所レコードのリストを作成する
+/// 形式が不正な行は読み飛ばし、行ごとのエラーのリストとして住所レコードとともに返す
+pub fn jigyosyo_records<R: Read>(
+    reader: R,
+    schema: &CsvSchema<JigyosyoColumns>,
+) -> Result<(Vec<PostalCodeRecord>, Vec<IngestError>), IngestError> {
+    let columns = &schema.columns;
+
+    // CSVのそれぞれの行を分割しながら、リストに格納する
+    let mut postal_code_record_list = Vec::<PostalCodeRecord>::new();
+    let mut row_errors = Vec::<IngestError>::new();
+    for result in schema.records(reader) {
+        let record = match result {
+            Ok(record) => record,
+            Err(err) if err.is_row_error() => {
+                row_errors.push(err);
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        let row = row_number(&record);
+
+        let national_local_government_code = &record[columns.national_local_government_code]; //大口事業所の所在地のJISコード 半角数字
+        let name_kana = &record[columns.name_kana]; //大口事業所名カナ 半角カタカナ
+        let name = &record[columns.name]; //大口事業所名
+        let prefecture = &record[columns.prefecture]; //都道府県名
+        let city = &record[columns.city]; //市区町村名
+        let town = &record[columns.town]; //町域名
+        let address = &record[columns.address]; //小字名、丁目、番地等
+        let postal_code = &record[columns.postal_code]; //大口事業所個別番号 半角数字
+        let handling_office = &record[columns.handling_office]; //取扱局
+        let is_post_office_box = &record[columns.is_post_office_box] == "1"; //個別番号の種別の表示　「0」大口事業所、「1」私書箱
+
+        let is_change = &record[columns.is_change]; //修正コード　「0」修正なし、「1」新規追加、「5」廃止
+
+        let changed = match is_change {
+            "0" => postal_code_record::Changed::NoChange,
+            "1" => postal_code_record::Changed::Changed,
+            "5" => postal_code_record::Changed::Deleted,
+            _ => {
+                row_errors.push(IngestError::InvalidRow {
+                    file: schema.file,
+                    row,
+                    message: format!("unknown changed value: {}", is_change),
+                });
+                continue;
+            }
+        };
+
+        let postal_code_record = PostalCodeRecord::new_business(
+            national_local_government_code.to_owned(),
+            postal_code.to_owned(),
+            prefecture.to_owned(),
+            city.to_owned(),
+            town.to_owned(),
+            BusinessInfo {
+                name: name.to_owned(),
+                //住所のカナと同じく全角カタカナで保存する
+                name_kana: kana::hankaku_to_zenkaku(name_kana),
+                address: address.to_owned(),
+                handling_office: handling_office.to_owned(),
+                is_post_office_box,
+            },
+            changed,
+        );
+        postal_code_record_list.push(postal_code_record);
+    }
+
+    return Ok((postal_code_record_list, row_errors));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postal_code_record::{Changed, RecordType};
+
+    #[test]
+    fn read_jigyosyo_row() {
+        let csv = concat!(
+            "01101,\"(ｶﾌﾞ) ﾆﾂﾎﾟﾝｹｲｻﾞｲｼﾝﾌﾞﾝｼﾔ ｻﾂﾎﾟﾛｼｼﾔ\",\"株式会社　日本経済新聞社　札幌支社\",\"北海道\",\"札幌市中央区\",\"北一条西\",\"６丁目１－２アーバンネット札幌ビル２Ｆ\",\"0608621\",\"060  \",\"札幌中央\",0,0,0\n",
+            "01101,\"ｻﾂﾎﾟﾛﾁﾕｳｵｳﾕｳﾋﾞﾝｷﾖｸ ｼｼﾖﾊﾞｺ\",\"札幌中央郵便局　私書箱\",\"北海道\",\"札幌市中央区\",\"北二条西\",\"４丁目\",\"0608799\",\"060  \",\"札幌中央\",1,0,1\n",
+            "01101,\"ﾎｯｶｲﾄﾞｳﾁｮｳ\",\"北海道庁\",\"北海道\",\"札幌市中央区\",\"北三条西\",\"６丁目\",\"0608588\",\"060  \",\"札幌中央\",0,0,5\n",
+        );
+
+        let (records, row_errors) = jigyosyo_records(csv.as_bytes(), &JIGYOSYO_SCHEMA).unwrap();
+
+        assert!(row_errors.is_empty());
+        assert_eq!(records.len(), 3);
+        let record = &records[0];
+        assert_eq!(record.record_type, RecordType::Business);
+        assert_eq!(record.national_local_government_code, "01101");
+        assert_eq!(record.postal_code, "0608621");
+        assert_eq!(record.prefecture, "北海道");
+        assert_eq!(record.city, "札幌市中央区");
+        assert_eq!(record.town, "北一条西");
+        assert_eq!(record.is_change, Changed::NoChange);
+        // 大口事業所のデータには変更理由がない
+        assert_eq!(record.change_reason, None);
+        let business = record.business.as_ref().unwrap();
+        assert_eq!(business.name, "株式会社　日本経済新聞社　札幌支社");
+        assert_eq!(
+            business.name_kana,
+            "（カブ）　ニツポンケイザイシンブンシヤ　サツポロシシヤ"
+        );
+        assert_eq!(business.address, "６丁目１－２アーバンネット札幌ビル２Ｆ");
+        assert_eq!(business.handling_office, "札幌中央");
+        assert!(!business.is_post_office_box);
+
+        assert!(records[1].business.as_ref().unwrap().is_post_office_box);
+        assert_eq!(records[1].is_change, Changed::Changed);
+        assert_eq!(records[2].is_change, Changed::Deleted);
+    }
+
+    #[test]
+    fn unknown_change_code_is_row_error() {
+        let csv = "01101,\"ﾎｯｶｲﾄﾞｳﾁｮｳ\",\"北海道庁\",\"北海道\",\"札幌市中央区\",\"北三条西\",\"６丁目\",\"0608588\",\"060  \",\"札幌中央\",0,0,2\n";
+
+        let (records, row_errors) = jigyosyo_records(csv.as_bytes(), &JIGYOSYO_SCHEMA).unwrap();
+
+        assert!(records.is_empty());
+        assert_eq!(row_errors.len(), 1);
+        assert!(row_errors[0].is_row_error());
+        assert_eq!(row_errors[0].row(), Some(1));
+    }
+}
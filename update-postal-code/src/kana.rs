@@ -0,0 +1,182 @@
+// 全角カタカナと半角カタカナの対応表
+// 濁音・半濁音は半角では濁点・半濁点を含む2文字で表現される
+// ヮ・ヰ・ヱ・ヵ・ヶは対応する半角カタカナがないため、近い文字に変換し、半角から全角への変換でワ・イ・エ・カ・ケが優先されるように後ろに置く
+const KANA_TABLE: [(char, &str); 96] = [
+    ('ァ', "ｧ"),
+    ('ア', "ｱ"),
+    ('ィ', "ｨ"),
+    ('イ', "ｲ"),
+    ('ゥ', "ｩ"),
+    ('ウ', "ｳ"),
+    ('ェ', "ｪ"),
+    ('エ', "ｴ"),
+    ('ォ', "ｫ"),
+    ('オ', "ｵ"),
+    ('カ', "ｶ"),
+    ('ガ', "ｶﾞ"),
+    ('キ', "ｷ"),
+    ('ギ', "ｷﾞ"),
+    ('ク', "ｸ"),
+    ('グ', "ｸﾞ"),
+    ('ケ', "ｹ"),
+    ('ゲ', "ｹﾞ"),
+    ('コ', "ｺ"),
+    ('ゴ', "ｺﾞ"),
+    ('サ', "ｻ"),
+    ('ザ', "ｻﾞ"),
+    ('シ', "ｼ"),
+    ('ジ', "ｼﾞ"),
+    ('ス', "ｽ"),
+    ('ズ', "ｽﾞ"),
+    ('セ', "ｾ"),
+    ('ゼ', "ｾﾞ"),
+    ('ソ', "ｿ"),
+    ('ゾ', "ｿﾞ"),
+    ('タ', "ﾀ"),
+    ('ダ', "ﾀﾞ"),
+    ('チ', "ﾁ"),
+    ('ヂ', "ﾁﾞ"),
+    ('ッ', "ｯ"),
+    ('ツ', "ﾂ"),
+    ('ヅ', "ﾂﾞ"),
+    ('テ', "ﾃ"),
+    ('デ', "ﾃﾞ"),
+    ('ト', "ﾄ"),
+    ('ド', "ﾄﾞ"),
+    ('ナ', "ﾅ"),
+    ('ニ', "ﾆ"),
+    ('ヌ', "ﾇ"),
+    ('ネ', "ﾈ"),
+    ('ノ', "ﾉ"),
+    ('ハ', "ﾊ"),
+    ('バ', "ﾊﾞ"),
+    ('パ', "ﾊﾟ"),
+    ('ヒ', "ﾋ"),
+    ('ビ', "ﾋﾞ"),
+    ('ピ', "ﾋﾟ"),
+    ('フ', "ﾌ"),
+    ('ブ', "ﾌﾞ"),
+    ('プ', "ﾌﾟ"),
+    ('ヘ', "ﾍ"),
+    ('ベ', "ﾍﾞ"),
+    ('ペ', "ﾍﾟ"),
+    ('ホ', "ﾎ"),
+    ('ボ', "ﾎﾞ"),
+    ('ポ', "ﾎﾟ"),
+    ('マ', "ﾏ"),
+    ('ミ', "ﾐ"),
+    ('ム', "ﾑ"),
+    ('メ', "ﾒ"),
+    ('モ', "ﾓ"),
+    ('ャ', "ｬ"),
+    ('ヤ', "ﾔ"),
+    ('ュ', "ｭ"),
+    ('ユ', "ﾕ"),
+    ('ョ', "ｮ"),
+    ('ヨ', "ﾖ"),
+    ('ラ', "ﾗ"),
+    ('リ', "ﾘ"),
+    ('ル', "ﾙ"),
+    ('レ', "ﾚ"),
+    ('ロ', "ﾛ"),
+    ('ワ', "ﾜ"),
+    ('ヲ', "ｦ"),
+    ('ン', "ﾝ"),
+    ('ヴ', "ｳﾞ"),
+    ('ヷ', "ﾜﾞ"),
+    ('ヺ', "ｦﾞ"),
+    ('ヮ', "ﾜ"),
+    ('ヰ', "ｲ"),
+    ('ヱ', "ｴ"),
+    ('ヵ', "ｶ"),
+    ('ヶ', "ｹ"),
+    ('・', "･"),
+    ('ー', "ｰ"),
+    ('「', "｢"),
+    ('」', "｣"),
+    ('、', "､"),
+    ('。', "｡"),
+    ('゛', "ﾞ"),
+    ('゜', "ﾟ"),
+];
+
+/// 半角カタカナを全角カタカナに変換する
+/// 濁点・半濁点が続く場合は一つの文字に合成する (例: ｶﾞ → ガ)
+/// 半角英数記号も全角英数記号に変換する
+pub fn hankaku_to_zenkaku(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        //濁点・半濁点が続いていれば、合成した文字が対応表にあるか探す
+        if let Some(&mark) = chars.peek() {
+            if mark == 'ﾞ' || mark == 'ﾟ' {
+                let composed = format!("{}{}", c, mark);
+                if let Some((zenkaku, _)) =
+                    KANA_TABLE.iter().find(|(_, hankaku)| *hankaku == composed)
+                {
+                    result.push(*zenkaku);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+
+        //対応表では半角カタカナが重複するヮ・ヰ・ヱ・ヵ・ヶを通常の文字より後ろに置いているので、最初に見つかったものを使う
+        let mut buf = [0; 4];
+        let single: &str = c.encode_utf8(&mut buf);
+        if let Some((zenkaku, _)) = KANA_TABLE.iter().find(|(_, hankaku)| *hankaku == single) {
+            result.push(*zenkaku);
+        } else {
+            result.push(hankaku_alphanumeric_to_zenkaku(c));
+        }
+    }
+
+    return result;
+}
+
+/// 半角英数記号を全角英数記号に変換
+fn hankaku_alphanumeric_to_zenkaku(c: char) -> char {
+    match c {
+        // '!'..='~' = '\u{0021}'..='\u{007E}'
+        '\u{0021}'..='\u{007E}' => char::from_u32(c as u32 - 0x21 + 0xFF01).unwrap_or(c),
+        // space
+        ' ' => '\u{3000}',
+        // others
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compose_dakuten_and_handakuten() {
+        assert_eq!(hankaku_to_zenkaku("ｶﾞｯｺｳ"), "ガッコウ");
+        assert_eq!(hankaku_to_zenkaku("ﾊﾟﾝ"), "パン");
+        assert_eq!(hankaku_to_zenkaku("ｳﾞｨﾗ"), "ヴィラ");
+        assert_eq!(hankaku_to_zenkaku("ﾜﾞ"), "ヷ");
+    }
+
+    #[test]
+    fn keep_mark_that_cannot_be_composed() {
+        assert_eq!(hankaku_to_zenkaku("ｱﾞ"), "ア゛");
+        assert_eq!(hankaku_to_zenkaku("ﾟ"), "゜");
+    }
+
+    #[test]
+    fn prefer_normal_kana_over_duplicated_kana() {
+        assert_eq!(hankaku_to_zenkaku("ｲｴｶｹﾜ"), "イエカケワ");
+    }
+
+    #[test]
+    fn convert_alphanumeric_and_symbols() {
+        assert_eq!(hankaku_to_zenkaku("ﾋﾞﾙ 1F(A-1)"), "ビル　１Ｆ（Ａ－１）");
+        assert_eq!(hankaku_to_zenkaku("｢ｱ｣､ｲ｡ｰ･"), "「ア」、イ。ー・");
+    }
+
+    #[test]
+    fn keep_zenkaku_kana() {
+        assert_eq!(hankaku_to_zenkaku("オオドオリニシ"), "オオドオリニシ");
+    }
+}
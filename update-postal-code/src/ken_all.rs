@@ -1,265 +1,947 @@
 use base64::{engine::general_purpose, Engine as _};
-use digest::FixedOutputReset;
 use regex::Regex;
-use reqwest::blocking::Response;
-use std::{
-    collections::HashMap,
-    io::{Cursor, Read, Seek},
-};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::Read;
 
-use encoding_rs::SHIFT_JIS;
 use sha2::{Digest, Sha256};
-use zip::ZipArchive;
 
-use crate::postal_code_record::{self, PostalCodeRecord};
+use crate::csv_schema::{CsvRecords, CsvSchema};
+use crate::error::{row_number, IngestError};
+use crate::kana;
+use crate::ken_all_rome::{self, AttachRoma, RomeJoin};
+use crate::postal_code_record::{self, PostalCodeRecord, TownAnnotation};
+use crate::source::{Source, SourceContents, TextReader};
+use crate::town_name;
+
+/// 日本郵便が公開しているken_allのファイル形式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceFormat {
+    /// ken_all.zip Shift-JIS、半角カナ、長い町域名は複数行に分割される
+    Legacy,
+    /// utf_ken_all.zip UTF-8、全角カナ、町域名は複数行に分割されない
+    Utf8,
+}
+
+impl SourceFormat {
+    /// 環境変数などで指定された文字列からファイル形式を取得する
+    pub fn from_name(name: &str) -> Option<SourceFormat> {
+        match name {
+            "legacy" => Some(SourceFormat::Legacy),
+            "utf8" => Some(SourceFormat::Utf8),
+            _ => None,
+        }
+    }
+
+    /// 月次の差分ファイル(追加データ, 削除データ)のURLを取得する
+    /// diff_monthは年月をYYMM形式で指定する
+    fn diff_urls(&self, diff_month: &str) -> (String, String) {
+        match self {
+            SourceFormat::Legacy => (
+                format!("https://www.post.japanpost.jp/zipcode/dl/kogaki/zip/add_{diff_month}.zip"),
+                format!("https://www.post.japanpost.jp/zipcode/dl/kogaki/zip/del_{diff_month}.zip"),
+            ),
+            SourceFormat::Utf8 => (
+                format!("https://www.post.japanpost.jp/zipcode/utf/zip/utf_add_{diff_month}.zip"),
+                format!("https://www.post.japanpost.jp/zipcode/utf/zip/utf_del_{diff_month}.zip"),
+            ),
+        }
+    }
+
+    pub fn url(&self) -> &'static str {
+        match self {
+            SourceFormat::Legacy => {
+                "https://www.post.japanpost.jp/zipcode/dl/kogaki/zip/ken_all.zip"
+            }
+            SourceFormat::Utf8 => "https://www.post.japanpost.jp/zipcode/utf/zip/utf_ken_all.zip",
+        }
+    }
+}
+
+pub struct KenAllDiffData {
+    pub added_postal_code_record_list: Vec<PostalCodeRecord>,
+    pub deleted_postal_code_record_list: Vec<PostalCodeRecord>,
+    // 形式が不正で読み飛ばした行
+    pub invalid_rows: Vec<IngestError>,
+}
 
-pub struct KenAllData {
-    pub all_contents_hash: String,
-    pub grouped_postal_code_record_list: HashMap<String, Vec<PostalCodeRecord>>,
-    pub national_local_government_code_to_hash: HashMap<String, String>,
+/// ken_allの各項目の列の位置
+#[derive(Debug, Clone, Copy)]
+pub struct KenAllColumns {
+    pub national_local_government_code: usize,
+    pub postal_code: usize,
+    pub prefecture_kana: usize,
+    pub city_kana: usize,
+    pub town_kana: usize,
+    pub prefecture: usize,
+    pub city: usize,
+    pub town: usize,
+    pub 一つの町域が二つ以上の郵便番号で表示される: usize,
+    pub 小字毎に番地が起番されている町域: usize,
+    pub 丁目を有する町域: usize,
+    pub 一つの郵便番号で二以上の町域を表す: usize,
+    pub is_change: usize,
+    pub change_reason: usize,
 }
 
-pub fn ken_all_records() -> KenAllData {
-    //let filepath = std::path::Path::new("ken_all.zip");
-    //let reader = std::fs::File::open(filepath).expect("Unable to open the file");
-
-    //ken_all.zipをダウンロードする
-    let mut response = download_ken_all_zip().expect("Unable to download the zip file");
-    let reader =
-        response_to_cursor(&mut response).expect("Unable to convert the response to cursor");
-
-    //Zipファイルからファイルを一つ取り出して、その内容を文字列にして取得する
-    let contents =
-        zip_to_file_contents(reader).expect("Unable to convert the zip to file contents");
-
-    // CSVファイルをパース
-    let mut reader = csv::Reader::from_reader(contents.as_bytes());
-
-    // CSVのそれぞれの行を分割しながら、リストに格納する
-    let mut postal_code_record_list = Vec::<postal_code_record::PostalCodeRecord>::new();
-    for result in reader.records() {
-        let record = result.expect("Unable to read the record");
-        //レコードの長さが不正であれば処理をしない
-        if record.len() < 15 {
-            //TODO ログ出力
-            continue;
+/// ken_all.zip・utf_ken_all.zipと月次の差分ファイルの形式 (ヘッダー行はない)
+pub const KEN_ALL_SCHEMA: CsvSchema<KenAllColumns> = CsvSchema {
+    file: "ken_all",
+    has_headers: false,
+    delimiter: b',',
+    quoting: true,
+    column_count: 15,
+    columns: KenAllColumns {
+        national_local_government_code: 0,
+        postal_code: 2,
+        prefecture_kana: 3,
+        city_kana: 4,
+        town_kana: 5,
+        prefecture: 6,
+        city: 7,
+        town: 8,
+        一つの町域が二つ以上の郵便番号で表示される: 9,
+        小字毎に番地が起番されている町域: 10,
+        丁目を有する町域: 11,
+        一つの郵便番号で二以上の町域を表す: 12,
+        is_change: 13,
+        change_reason: 14,
+    },
+};
+
+/// ken_allのCSVを一行ずつ読み込み、正規化・ローマ字の結合・町域名の展開を行った住所レコードを順に返すイテレーター
+/// 複数行に分割された町域名は、分割された行をまとめて読み込んでから正規化する
+pub struct KenAllRecords<'a, R: Read> {
+    schema: CsvSchema<KenAllColumns>,
+    records: CsvRecords<R>,
+    rome: Box<dyn AttachRoma + 'a>,
+    //（...）にマッチする正規表現
+    zenkaku_bracket_regexp: Regex,
+    //正規化済みで、まだ返していない住所レコード
+    pending: VecDeque<PostalCodeRecord>,
+    //分割行の続きとして読み込んだが、郵便番号が異なっていたため次の住所の先頭行とする住所レコード
+    peeked: Option<PostalCodeRecord>,
+    //最後に読み込んだ行番号
+    row: u64,
+    //最後まで読み込んだか、読み込みを続けられないエラーが発生した場合にtrue
+    finished: bool,
+}
+
+impl<'a, R: Read> KenAllRecords<'a, R> {
+    pub fn new(
+        reader: R,
+        schema: CsvSchema<KenAllColumns>,
+        rome: Box<dyn AttachRoma + 'a>,
+    ) -> KenAllRecords<'a, R> {
+        KenAllRecords {
+            schema,
+            // CSVファイルをパース
+            records: schema.records(reader),
+            rome,
+            zenkaku_bracket_regexp: Regex::new(r"（(.*?)）").unwrap(),
+            pending: VecDeque::new(),
+            peeked: None,
+            row: 0,
+            finished: false,
         }
+    }
 
-        let national_local_government_code = record.get(0).unwrap(); //全国地方公共団体コード 半角数字
-        let postal_code = record.get(2).unwrap(); //郵便番号 半角数字
-        let prefecture_kana = record.get(3).unwrap(); //都道府県名カナ 半角カタカナ
-        let city_kana = record.get(4).unwrap(); //市区町村名カナ 半角カタカナ
-        let town_kana = record.get(5).unwrap(); //町域名カナ 半角カタカナ
-        let prefecture = record.get(6).unwrap(); //都道府県名
-        let city = record.get(7).unwrap(); //市区町村名
-        let town = record.get(8).unwrap(); //町域名
-        let is_一つの町域が二つ以上の郵便番号で表示される = record.get(9).unwrap() == "1";
-        let is_小字毎に番地が起番されている町域 = record.get(10).unwrap() == "1";
-        let is_丁目を有する町域 = record.get(11).unwrap() == "1";
-        let is_一つの郵便番号で二以上の町域を表す = record.get(12).unwrap() == "1";
-
-        let is_change = i32::from_str_radix(record.get(13).unwrap(), 10).unwrap(); //更新の表示　「0」は変更なし、「1」は変更あり、「2」廃止（廃止データのみ使用）
-
-        //現状では使用していないのでコメントアウト
-        //let change_reason = i32::from_str_radix(record.get(14).unwrap(), 10).unwrap(); //変更理由
-
-        let postal_code_record = postal_code_record::PostalCodeRecord::new(
-            national_local_government_code.to_owned(),
-            postal_code.to_owned(),
-            prefecture_kana.to_owned(),
-            city_kana.to_owned(),
-            town_kana.to_owned(),
-            prefecture.to_owned(),
-            city.to_owned(),
-            town.to_owned(),
-            is_一つの町域が二つ以上の郵便番号で表示される,
-            is_小字毎に番地が起番されている町域,
-            is_丁目を有する町域,
-            is_一つの郵便番号で二以上の町域を表す,
-            match is_change {
-                0 => postal_code_record::Changed::NoChange,
-                1 => postal_code_record::Changed::Changed,
-                2 => postal_code_record::Changed::Deleted,
-                _ => panic!("Unknown Changed value: {}", is_change),
-            },
-        );
-        postal_code_record_list.push(postal_code_record);
+    // 次の行を読み込んで住所レコードに変換する (最後まで読み込んでいればNoneを返す)
+    fn read_record(&mut self) -> Result<Option<PostalCodeRecord>, IngestError> {
+        if let Some(record) = self.peeked.take() {
+            return Ok(Some(record));
+        }
+
+        let record = match self.records.next() {
+            Some(result) => result?,
+            None => return Ok(None),
+        };
+        self.row = row_number(&record);
+
+        return Ok(Some(parse_ken_all_record(&record, &self.schema)?));
     }
 
-    //townとtown_kanaに対して、正規化を行う
-    record_normalize(&mut postal_code_record_list);
+    // 一つの住所を表す行(複数行に分割されていればそのすべての行)を読み込んで、正規化した住所レコードを溜めておく
+    // 最後まで読み込んでいればfalseを返す
+    fn read_chunk(&mut self) -> Result<bool, IngestError> {
+        let head = match self.read_record()? {
+            Some(head) => head,
+            None => return Ok(false),
+        };
+
+        //閉じていない開きカッコを含む場合は、カッコが閉じるまで同じ郵便番号の行を続けて読み込む
+        let mut rows = vec![head];
+        let mut depth = bracket_depth(&rows[0].town);
+        while depth > 0 {
+            let record = match self.read_record()? {
+                Some(record) => record,
+                None => break,
+            };
+            //郵便番号が異なる行は、閉じカッコが欠けた行の後に続く別の住所として扱う
+            if record.postal_code != rows[0].postal_code {
+                self.peeked = Some(record);
+                break;
+            }
+            depth += bracket_depth(&record.town);
+            rows.push(record);
+        }
+
+        //分割された行を一つの住所レコードに連結してから、townとtown_kanaに対して正規化を行う
+        let mut chunk = vec![merge_split_rows(rows)];
+        record_normalize(&mut chunk, &self.zenkaku_bracket_regexp);
+
+        //ローマ字の住所を結合する (町域名の展開前に行う)
+        self.rome.attach(&mut chunk)?;
+
+        //列挙・範囲指定された町域名を個別の町域に展開する
+        self.pending.extend(town_name::expand_town_names(chunk));
+
+        return Ok(true);
+    }
+}
+
+impl<'a, R: Read> Iterator for KenAllRecords<'a, R> {
+    type Item = Result<PostalCodeRecord, IngestError>;
 
-    // ハッシュを撮る前準備として安定した並び順にするために
-    // 住所レコードリストをnational_local_government_codeとpostal_codeでソートする
-    postal_code_record_list.sort_by(|a, b| {
-        let national_local_government_code_cmp = a
-            .national_local_government_code
-            .cmp(&b.national_local_government_code);
-        if national_local_government_code_cmp != std::cmp::Ordering::Equal {
-            return national_local_government_code_cmp;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Some(Ok(record));
+            }
+            if self.finished {
+                return None;
+            }
+
+            match self.read_chunk() {
+                Ok(true) => {}
+                Ok(false) => self.finished = true,
+                Err(err) => {
+                    //不正な行は読み飛ばして、次の行から読み込みを続ける
+                    if !err.is_row_error() {
+                        self.finished = true;
+                    }
+                    return Some(Err(err));
+                }
+            }
         }
+    }
+}
 
-        a.postal_code.cmp(&b.postal_code)
-    });
+// ken_allのCSVの一行を住所レコードに変換する
+fn parse_ken_all_record(
+    record: &csv::StringRecord,
+    schema: &CsvSchema<KenAllColumns>,
+) -> Result<PostalCodeRecord, IngestError> {
+    let row = row_number(record);
+    let columns = &schema.columns;
+
+    let national_local_government_code = &record[columns.national_local_government_code]; //全国地方公共団体コード 半角数字
+    let postal_code = &record[columns.postal_code]; //郵便番号 半角数字
+
+    //従来版のカナは半角カタカナなので、全角カタカナに揃えて保存する
+    //UTF-8版のカナは全角カタカナだが、英数記号が半角の場合があるため同じ変換を行う
+    let prefecture_kana = kana::hankaku_to_zenkaku(&record[columns.prefecture_kana]); //都道府県名カナ
+    let city_kana = kana::hankaku_to_zenkaku(&record[columns.city_kana]); //市区町村名カナ
+    let town_kana = kana::hankaku_to_zenkaku(&record[columns.town_kana]); //町域名カナ
+    let prefecture = &record[columns.prefecture]; //都道府県名
+    let city = &record[columns.city]; //市区町村名
+    let town = &record[columns.town]; //町域名
+    let is_一つの町域が二つ以上の郵便番号で表示される =
+        &record[columns.一つの町域が二つ以上の郵便番号で表示される] == "1";
+    let is_小字毎に番地が起番されている町域 =
+        &record[columns.小字毎に番地が起番されている町域] == "1";
+    let is_丁目を有する町域 = &record[columns.丁目を有する町域] == "1";
+    let is_一つの郵便番号で二以上の町域を表す =
+        &record[columns.一つの郵便番号で二以上の町域を表す] == "1";
+
+    let is_change = &record[columns.is_change]; //更新の表示　「0」は変更なし、「1」は変更あり、「2」廃止（廃止データのみ使用）
+    let change_reason = &record[columns.change_reason]; //変更理由
+    let change_reason = match postal_code_record::ChangeReason::from_code(change_reason) {
+        Some(change_reason) => change_reason,
+        None => {
+            return Err(IngestError::InvalidRow {
+                file: schema.file,
+                row,
+                message: format!("unknown change reason value: {}", change_reason),
+            })
+        }
+    };
+
+    let mut postal_code_record = postal_code_record::PostalCodeRecord::new(
+        national_local_government_code.to_owned(),
+        postal_code.to_owned(),
+        prefecture_kana,
+        city_kana,
+        town_kana,
+        prefecture.to_owned(),
+        city.to_owned(),
+        town.to_owned(),
+        is_一つの町域が二つ以上の郵便番号で表示される,
+        is_小字毎に番地が起番されている町域,
+        is_丁目を有する町域,
+        is_一つの郵便番号で二以上の町域を表す,
+        match is_change {
+            "0" => postal_code_record::Changed::NoChange,
+            "1" => postal_code_record::Changed::Changed,
+            "2" => postal_code_record::Changed::Deleted,
+            _ => {
+                return Err(IngestError::InvalidRow {
+                    file: schema.file,
+                    row,
+                    message: format!("unknown changed value: {}", is_change),
+                })
+            }
+        },
+    );
+    postal_code_record.change_reason = Some(change_reason);
+
+    return Ok(postal_code_record);
+}
+
+/// 住所レコードをnational_local_government_codeごとにまとめて、コードの順に返すイテレーター
+/// ken_allはnational_local_government_codeの順に並んでいるため、一つの市区町村分の住所レコードだけを保持すればよい
+/// 大口事業所個別番号のデータは、同じnational_local_government_codeの住所レコードの後ろに加える
+/// 形式が不正で読み飛ばした行は、行ごとのエラーとして市区町村の住所レコードとは別に返す
+pub struct MunicipalityGroups<'a, R: Read> {
+    records: KenAllRecords<'a, R>,
+    //読み飛ばした不正な行のうち、まだ返していないもの
+    row_errors: VecDeque<IngestError>,
+    //先読みした次の市区町村の住所レコード
+    peeked: Option<PostalCodeRecord>,
+    //大口事業所個別番号のデータ (件数が少なく、並び順も保証されていないため、まとめて読み込んでおく)
+    business_records: BTreeMap<String, Vec<PostalCodeRecord>>,
+    //最後に返したken_allのnational_local_government_code (並び順の確認に使用する)
+    last_code: Option<String>,
+    finished: bool,
+}
+
+impl<'a, R: Read> MunicipalityGroups<'a, R> {
+    pub fn new(
+        records: KenAllRecords<'a, R>,
+        business_records: Vec<PostalCodeRecord>,
+        business_row_errors: Vec<IngestError>,
+    ) -> MunicipalityGroups<'a, R> {
+        let mut grouped_business_records = BTreeMap::<String, Vec<PostalCodeRecord>>::new();
+        for record in business_records {
+            grouped_business_records
+                .entry(record.national_local_government_code.clone())
+                .or_default()
+                .push(record);
+        }
+
+        MunicipalityGroups {
+            records,
+            row_errors: business_row_errors.into(),
+            peeked: None,
+            business_records: grouped_business_records,
+            last_code: None,
+            finished: false,
+        }
+    }
 
-    // 住所レコードリストをnational_local_government_codeでグルーピングする
-    let mut grouped_postal_code_record_list =
-        HashMap::<String, Vec<postal_code_record::PostalCodeRecord>>::new();
-    for record in postal_code_record_list {
-        grouped_postal_code_record_list
-            .entry(record.national_local_government_code.clone())
-            .or_insert_with(|| Vec::new())
-            .push(record);
+    // ken_allの次の住所レコードを取得する (不正な行は後で返すために溜めておく)
+    fn next_record(&mut self) -> Result<Option<PostalCodeRecord>, IngestError> {
+        loop {
+            match self.records.next() {
+                Some(Err(err)) if err.is_row_error() => self.row_errors.push_back(err),
+                result => return result.transpose(),
+            }
+        }
     }
 
-    //national_local_government_codeとハッシュ値のペアを格納するリスト
-    let mut national_local_government_code_to_hash = HashMap::<String, String>::new();
+    // 次の市区町村の住所レコードをまとめて取得する (廃止データを除いた結果、空になることもある)
+    fn next_group(&mut self) -> Result<Option<(String, Vec<PostalCodeRecord>)>, IngestError> {
+        if self.peeked.is_none() {
+            self.peeked = self.next_record()?;
+        }
+
+        //ken_allの次の市区町村よりコードが小さい大口事業所個別番号のみの市区町村を先に返す
+        let next_business_code = self.business_records.keys().next().cloned();
+        let code = match (&self.peeked, next_business_code) {
+            (Some(peeked), Some(business_code))
+                if business_code < peeked.national_local_government_code =>
+            {
+                business_code
+            }
+            (Some(peeked), _) => peeked.national_local_government_code.clone(),
+            (None, Some(business_code)) => business_code,
+            (None, None) => return Ok(None),
+        };
+
+        let mut group = Vec::<PostalCodeRecord>::new();
+        while self
+            .peeked
+            .as_ref()
+            .is_some_and(|peeked| peeked.national_local_government_code == code)
+        {
+            group.push(self.peeked.take().unwrap());
+            self.peeked = self.next_record()?;
+        }
+
+        if !group.is_empty() {
+            //一度まとめた市区町村の住所レコードが後から現れた場合は、並び順が想定と異なるためエラー
+            if self
+                .last_code
+                .as_ref()
+                .is_some_and(|last_code| last_code >= &code)
+            {
+                return Err(IngestError::NotSorted {
+                    file: KEN_ALL_SCHEMA.file,
+                    row: self.records.row,
+                    national_local_government_code: code,
+                });
+            }
+            self.last_code = Some(code.clone());
+        }
+        if let Some(business_records) = self.business_records.remove(&code) {
+            group.extend(business_records);
+        }
 
+        //廃止データは登録しない (前回の更新時から消滅した郵便番号として削除される)
+        group.retain(|record| !matches!(record.is_change, postal_code_record::Changed::Deleted));
+
+        // ハッシュを撮る前準備として安定した並び順にするために、市区町村内の住所レコードをpostal_codeでソートする
+        group.sort_by(|a, b| a.postal_code.cmp(&b.postal_code));
+
+        return Ok(Some((code, group)));
+    }
+}
+
+impl<'a, R: Read> Iterator for MunicipalityGroups<'a, R> {
+    type Item = Result<(String, Vec<PostalCodeRecord>), IngestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(err) = self.row_errors.pop_front() {
+                return Some(Err(err));
+            }
+            if self.finished {
+                return None;
+            }
+
+            match self.next_group() {
+                Ok(Some((_, group))) if group.is_empty() => {}
+                Ok(Some(group)) => return Some(Ok(group)),
+                Ok(None) => self.finished = true,
+                Err(err) => {
+                    self.finished = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// 読み込み元のデータから、national_local_government_codeごとにまとめた住所レコードを順に返すイテレーターを作成する
+/// ken_allは読み込みながら処理するため、先頭から読み直す場合は再度呼び出す
+/// ローマ字表記もKEN_ALL_ROMEをken_allと並べて先頭から読み込みながら結合する
+pub fn municipality_groups(
+    contents: &SourceContents,
+) -> Result<MunicipalityGroups<'static, TextReader>, IngestError> {
+    //大口事業所個別番号のデータも同じ市区町村の住所レコードとしてまとめる
+    let (business_records, business_row_errors) = crate::jigyosyo::jigyosyo_records(
+        contents.jigyosyo.open()?,
+        &crate::jigyosyo::JIGYOSYO_SCHEMA,
+    )?;
+    let rome = RomeJoin::new(
+        contents.ken_all_rome.open()?,
+        &ken_all_rome::KEN_ALL_ROME_SCHEMA,
+    );
+    let records = KenAllRecords::new(contents.ken_all.open()?, KEN_ALL_SCHEMA, Box::new(rome));
+
+    return Ok(MunicipalityGroups::new(
+        records,
+        business_records,
+        business_row_errors,
+    ));
+}
+
+/// 住所レコードのハッシュ値を、national_local_government_codeごととコンテンツ全体について計算する
+#[derive(Default)]
+pub struct ContentHasher {
     //コンテンツ全体に対するハッシュ計算用インスタンス
-    let mut all_content_hasher = Sha256::new();
-    //national_local_government_codeごとのハッシュ計算用インスタンス
-    let mut national_local_government_code_hasher = Sha256::new();
-    for (national_local_government_code, records) in &grouped_postal_code_record_list {
+    all_content_hasher: Sha256,
+}
+
+impl ContentHasher {
+    /// 一つの市区町村の住所レコードのハッシュ値を計算し、コンテンツ全体のハッシュにも反映する
+    pub fn group_hash(&mut self, records: &[PostalCodeRecord]) -> String {
+        //national_local_government_codeごとのハッシュ計算用インスタンス
+        let mut national_local_government_code_hasher = Sha256::new();
         for record in records {
             //コンテンツの内容をハッシュに反映
-            record.hasher_add(&mut all_content_hasher);
+            record.hasher_add(&mut self.all_content_hasher);
             record.hasher_add(&mut national_local_government_code_hasher);
         }
-        let hash = national_local_government_code_hasher.finalize_fixed_reset();
-        // ハッシュ値をbase64に変換
-        let encoded_hash = general_purpose::STANDARD_NO_PAD.encode(hash);
 
-        // マップに保存
-        national_local_government_code_to_hash
-            .insert(national_local_government_code.to_owned(), encoded_hash);
+        // ハッシュ値をバージョン付きのbase64に変換
+        return encode_hash(&national_local_government_code_hasher.finalize());
     }
 
-    let hash = all_content_hasher.finalize();
-    // ハッシュ値をbase64に変換
-    let encoded_hash = general_purpose::STANDARD_NO_PAD.encode(hash);
-
-    return KenAllData {
-        all_contents_hash: encoded_hash,
-        grouped_postal_code_record_list: grouped_postal_code_record_list,
-        national_local_government_code_to_hash: national_local_government_code_to_hash,
-    };
+    /// コンテンツ全体のハッシュ値を取得する
+    pub fn finish(self) -> String {
+        // ハッシュ値をバージョン付きのbase64に変換
+        return encode_hash(&self.all_content_hasher.finalize());
+    }
 }
 
-fn download_ken_all_zip() -> Option<Response> {
-    let url = "https://www.post.japanpost.jp/zipcode/dl/kogaki/zip/ken_all.zip";
+/// ハッシュ値を保存用の文字列に変換する
+/// ハッシュの計算方法が変わった場合に以前のハッシュ値と一致しないように、先頭にバージョンを付与する
+fn encode_hash(hash: &[u8]) -> String {
+    format!(
+        "v{}:{}",
+        postal_code_record::HASH_FORMAT_VERSION,
+        general_purpose::STANDARD_NO_PAD.encode(hash)
+    )
+}
 
-    match reqwest::blocking::get(url) {
-        Ok(response) => {
-            if response.status() == reqwest::StatusCode::OK {
-                return Some(response);
-            } else {
-                //TODO ログ出力
-                return None;
-            }
+/// 月次の差分ファイル(ADD_YYMM.zip, DEL_YYMM.zip)から追加データと削除データを取得する
+pub fn ken_all_diff_records(
+    format: SourceFormat,
+    diff_month: &str,
+    rome_source: &Source,
+) -> Result<KenAllDiffData, IngestError> {
+    let (add_url, del_url) = format.diff_urls(diff_month);
+    let read = |source: &Source| {
+        tracing::info!(source = ?source, "read source");
+        source.read()
+    };
+    let add_data = read(&Source::Url(add_url))?;
+    let del_data = read(&Source::Url(del_url))?;
+
+    //ローマ字の差分ファイルは提供されていないため、全件のファイルから差分ファイルに含まれる郵便番号のみを読み込んで結合する
+    //差分ファイルはken_allの一部の行しか含まないため、並べて読み込まずに対応表を作成する
+    let mut postal_codes = HashSet::<String>::new();
+    for data in [&add_data, &del_data] {
+        //不正な行は住所レコードを読み込む際に記録する
+        for record in KEN_ALL_SCHEMA.records(data.open()?).flatten() {
+            postal_codes.insert(record[KEN_ALL_SCHEMA.columns.postal_code].to_owned());
         }
-        Err(e) => {
-            //TODO ログ出力
-            return None;
+    }
+    let rome_table = ken_all_rome::ken_all_rome_table(
+        read(rome_source)?.open()?,
+        &ken_all_rome::KEN_ALL_ROME_SCHEMA,
+        &postal_codes,
+    )?;
+
+    //差分ファイルは件数が少ないため、すべての住所レコードをまとめて取得する
+    let mut invalid_rows = Vec::<IngestError>::new();
+    let added_postal_code_record_list = collect_records(
+        KenAllRecords::new(add_data.open()?, KEN_ALL_SCHEMA, Box::new(&rome_table)),
+        &mut invalid_rows,
+    )?;
+    let deleted_postal_code_record_list = collect_records(
+        KenAllRecords::new(del_data.open()?, KEN_ALL_SCHEMA, Box::new(&rome_table)),
+        &mut invalid_rows,
+    )?;
+
+    return Ok(KenAllDiffData {
+        added_postal_code_record_list,
+        deleted_postal_code_record_list,
+        invalid_rows,
+    });
+}
+
+// 住所レコードをすべて読み込む (不正な行は読み飛ばしてinvalid_rowsに加える)
+fn collect_records<R: Read>(
+    records: KenAllRecords<R>,
+    invalid_rows: &mut Vec<IngestError>,
+) -> Result<Vec<PostalCodeRecord>, IngestError> {
+    let mut postal_code_record_list = Vec::<PostalCodeRecord>::new();
+    for result in records {
+        match result {
+            Ok(record) => postal_code_record_list.push(record),
+            Err(err) if err.is_row_error() => invalid_rows.push(err),
+            Err(err) => return Err(err),
         }
     }
+
+    return Ok(postal_code_record_list);
 }
 
-fn response_to_cursor(response: &mut Response) -> Option<Cursor<Vec<u8>>> {
-    let mut buf = Vec::new();
-    match response.copy_to(&mut buf) {
-        Ok(_) => {
-            let mut cursor = Cursor::new(buf);
-            cursor.seek(std::io::SeekFrom::Start(0)).unwrap();
+/// 町域名が住所を表さない説明文であればtrueを返す
+pub fn is_unnecessary_town(town: &str) -> bool {
+    // townが「以下に掲載がない場合」
+    town == "以下に掲載がない場合"
+        // townに「の次に番地が来る場合」が含まれている
+        || town.contains("の次に番地が来る場合")
+        // townが「一円」と完全一致せず、「一円」を含む
+        || (town != "一円" && town.contains("一円"))
+}
 
-            return Some(cursor);
-        }
-        Err(e) => {
-            //TODO ログ出力
-            return None;
+/// 文字列に含まれる開きカッコの数から閉じカッコの数を引いた値を返す
+/// 複数行に分割された町域名の続きの行を読み込むかの判定に使用する
+pub fn bracket_depth(town: &str) -> i32 {
+    let opened = town.matches('（').count() as i32;
+    let closed = town.matches('）').count() as i32;
+    return opened - closed;
+}
+
+// 複数行に分割された町域の行を、一つの住所レコードに連結する
+// 町域名は各行の内容をそのまま連結する
+// 町域名カナは分割されずに同じ値が各行に繰り返されている場合があるため、直前の行と異なる場合のみ連結する
+// 町域名以外の項目は先頭行の値を使用する
+fn merge_split_rows(rows: Vec<PostalCodeRecord>) -> PostalCodeRecord {
+    let mut rows = rows.into_iter();
+    let mut merged = rows.next().unwrap();
+    let mut previous_town_kana = merged.town_kana.clone();
+    for row in rows {
+        merged.town.push_str(&row.town);
+        if row.town_kana != previous_town_kana {
+            merged.town_kana.push_str(&row.town_kana);
         }
+        previous_town_kana = row.town_kana;
     }
+
+    return merged;
 }
 
-fn zip_to_file_contents<R: Read + std::io::Seek>(cursor: R) -> Option<String> {
-    let mut archive = ZipArchive::new(cursor).expect("Unable to open the zip file");
+// townとtown_kanaを正規化する (複数行に分割された町域は連結済みのものを渡す)
+// zenkaku_bracket_regexpは（...）にマッチする正規表現 (行ごとに作り直さないように呼び出し元で作成する)
+fn record_normalize(
+    postal_code_record_list: &mut [postal_code_record::PostalCodeRecord],
+    zenkaku_bracket_regexp: &Regex,
+) {
+    for record in postal_code_record_list.iter_mut() {
+        // townが住所を表していない場合は、不要な情報なのでクリア
+        if is_unnecessary_town(&record.town) {
+            record.town_kana = "".to_string();
+            record.town = "".to_string();
+        }
+
+        // 「、」で列挙された町名や「～」による範囲指定、地割を含む町名は、正規化後にtown_nameモジュールで処理する
+
+        // townに含まれる（から）までの文字列を注記として取得する
+        let mut annotation_texts = zenkaku_bracket_regexp
+            .captures_iter(&record.town)
+            .map(|captures| captures[1].to_string())
+            .collect::<Vec<_>>();
 
-    // Zipファイルに一つだけファイルが含まれているはずなので、そのファイルを取得
-    let mut zip_file = archive.by_index(0).expect("Unable to open the file");
+        // townとtown_kanaに含まれる（から）までの文字列をすべて削除する
+        record.town_kana = zenkaku_bracket_regexp
+            .replace_all(&record.town_kana, "")
+            .to_string();
+        record.town = zenkaku_bracket_regexp
+            .replace_all(&record.town, "")
+            .to_string();
 
-    // Zipファイルの内容を解凍してバイト配列に格納
-    let mut contents = Vec::new();
-    zip_file
-        .read_to_end(&mut contents)
-        .expect("Unable to read the file");
+        // 閉じカッコが見つからないまま分割行が終わった場合は、開きカッコより後ろの文字列を注記とする
+        if let Some(start_index) = record.town.find("（") {
+            annotation_texts.push(record.town[start_index + "（".len()..].to_string());
 
-    //文字コードがShift-JISになっているので、UTF-8に変換
-    let (decoded_contents, _, _) = SHIFT_JIS.decode(&contents);
+            //開きカッコより後ろのの文字列を削除する
+            record.town = record.town[..start_index].to_string();
+        }
+        //カナのtownに対しても同じ処理を適用する
+        if let Some(kana_start_index) = record.town_kana.find("（") {
+            //開きカッコより後ろのの文字列を削除する
+            record.town_kana = record.town_kana[..kana_start_index].to_string();
+        }
 
-    return Some(decoded_contents.to_string());
+        let annotation_text = annotation_texts.join("、");
+        if !annotation_text.is_empty() {
+            record.annotation = Some(parse_town_annotation(&annotation_text));
+        }
+    }
 }
 
-fn record_normalize(postal_code_record_list: &mut Vec<postal_code_record::PostalCodeRecord>) {
-    //（...）にマッチに正規表現
-    let zenkaku_bracket_regexp = Regex::new(r"（.*?）").unwrap();
-    let hankaku_bracket_regexp = Regex::new(r"\(.*?\)").unwrap();
+/// 町域名の括弧内の注記を解析して、階層・丁目・番地などに分類する
+fn parse_town_annotation(text: &str) -> TownAnnotation {
+    let mut annotation = TownAnnotation {
+        text: text.to_string(),
+        ..Default::default()
+    };
 
-    //分割行を統合中であればtrueになる
-    let mut integrated = false;
-    for record in postal_code_record_list.iter_mut() {
-        if !integrated {
-            // townが「以下に掲載がない場合」であれば不要な情報なのでクリア
-            if record.town == "以下に掲載がない場合" {
-                record.town_kana = "".to_string();
-                record.town = "".to_string();
-            }
-            // townに「の次に番地が来る場合」が含まれていれば、不要な情報なのでクリア
-            if record.town.contains("の次に番地が来る場合") {
-                record.town_kana = "".to_string();
-                record.town = "".to_string();
+    // 「を除く」「以外」で終わる場合は、除外する範囲を表している
+    let mut body = text;
+    if let Some(stripped) = body
+        .strip_suffix("を除く")
+        .or_else(|| body.strip_suffix("以外"))
+    {
+        annotation.excluded = true;
+        body = stripped;
+    }
+    // 「「２５１、２５３番地」以外」のように除外する範囲全体が「」で囲まれていれば、外して分割する
+    if let Some(inner) = body
+        .strip_prefix('「')
+        .and_then(|inner| inner.strip_suffix('」'))
+    {
+        if !inner.contains('「') {
+            body = inner;
+        }
+    }
+
+    // 「」の中にある「、」では分割しないように、読点で注記を分割する
+    let mut parts = Vec::<String>::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    for c in body.chars() {
+        match c {
+            '「' => {
+                depth += 1;
+                current.push(c);
             }
-            // townが「一円」と完全一致せず、「一円」を含むであれば、不要な情報なのでクリア
-            if record.town != "一円" && record.town.contains("一円") {
-                record.town_kana = "".to_string();
-                record.town = "".to_string();
+            '」' => {
+                depth -= 1;
+                current.push(c);
             }
+            '、' if depth == 0 => parts.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
 
-            //TODO: 地割に関する処理を行っていないに関する正規化処理は行っていない
-            // カンマ区切りで複数の町名が入っている、
-            // ～ で越中畑６４地割～越中畑６６地割のような町名がある
-
-            // townとtown_kanaに含まれる（から）までの文字列をすべて削除する
-            record.town_kana = hankaku_bracket_regexp
-                .replace_all(&record.town_kana, "")
-                .to_string();
-            record.town = zenkaku_bracket_regexp
-                .replace_all(&record.town, "")
-                .to_string();
-
-            // townが「（」を含んでいるなら
-            if let Some(start_index) = record.town.find("（") {
-                //開きカッコより後ろのの文字列を削除する
-                record.town = record.town[..start_index].to_string();
-                //半角のtownに対しても同じ処理を適用する
-                if let Some(hankaku_start_index) = record.town_kana.find("(") {
-                    //開きカッコより後ろのの文字列を削除する
-                    record.town_kana = record.town_kana[..hankaku_start_index].to_string();
-                }
-
-                //閉じカッコを含まない場合は、複数行にデータがまたがっているため閉じカッコが見つかるまで統合する
-                integrated = true;
-            }
+    // 「１９５、１９６番地」のように数字のみの要素は、後ろに続く単位の付いた要素と同じ分類にする
+    let mut pending_numbers = Vec::<String>::new();
+    for part in parts {
+        if part == "地階・階層不明" || part.ends_with('階') {
+            annotation.floor = Some(part);
+        } else if part.contains("地割") {
+            //地割は町域の地割番号として別途取り出すため、番地などとは分類しない
+            annotation.others.push(part);
+        } else if part.contains("丁目") {
+            annotation.chome.append(&mut pending_numbers);
+            annotation.chome.push(part);
+        } else if part.contains("番地") || part.ends_with('番') {
+            annotation.banchi.append(&mut pending_numbers);
+            annotation.banchi.push(part);
+        } else if is_number_part(&part) {
+            pending_numbers.push(part);
         } else {
-            //分割された行の統合中であれば不要な行なので統合フラグを立てておく
-            record.integrated = true;
-
-            // 閉じカッコを含んでいるか？
-            if record.town.contains("）") {
-                //統合処理終了
-                integrated = false;
-            }
+            annotation.others.push(part);
         }
     }
+    annotation.others.append(&mut pending_numbers);
+
+    return annotation;
+}
+
+// 数字と範囲を表す記号のみで構成された文字列か判定する
+fn is_number_part(part: &str) -> bool {
+    !part.is_empty()
+        && part.chars().all(|c| {
+            c.is_ascii_digit()
+                || ('０'..='９').contains(&c)
+                || "一二三四五六七八九十百千～－".contains(c)
+        })
+}
 
-    //Vecの中から統合されたレコードを削除する
-    postal_code_record_list.retain(|record| !record.integrated);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ken_all_rome::KEN_ALL_ROME_SCHEMA;
+
+    // ken_all(従来版)の形式のCSVを読み込んで、正規化後の住所レコードを取得する
+    fn read_ken_all(csv: &str) -> Vec<PostalCodeRecord> {
+        let rome = RomeJoin::new("".as_bytes(), &KEN_ALL_ROME_SCHEMA);
+        return KenAllRecords::new(csv.as_bytes(), KEN_ALL_SCHEMA, Box::new(rome))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+    }
+
+    #[test]
+    fn legacy_and_utf8_rows_are_same_record() {
+        // 同じ行を従来版(Shift-JIS、半角カナ)とUTF-8版(BOM付き、全角カナ)で読み込むと、同じ住所レコードになる
+        let legacy = "01101,\"060  \",\"0600042\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ｵｵﾄﾞｵﾘﾆｼ(1-19ﾁｮｳﾒ)\",\"北海道\",\"札幌市中央区\",\"大通西（１～１９丁目）\",1,0,1,0,0,0\r\n";
+        let utf8 = "\u{FEFF}01101,\"060  \",\"0600042\",\"ホッカイドウ\",\"サッポロシチュウオウク\",\"オオドオリニシ（１－１９チョウメ）\",\"北海道\",\"札幌市中央区\",\"大通西（１～１９丁目）\",1,0,1,0,0,0\r\n";
+        let read = |contents: Vec<u8>| {
+            let data = crate::source::SourceData::Csv(contents.into());
+            let rome = RomeJoin::new("".as_bytes(), &KEN_ALL_ROME_SCHEMA);
+            return KenAllRecords::new(data.open().unwrap(), KEN_ALL_SCHEMA, Box::new(rome))
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+        };
+
+        let (shift_jis, _, had_errors) = encoding_rs::SHIFT_JIS.encode(legacy);
+        assert!(!had_errors);
+        let legacy_records = read(shift_jis.into_owned());
+        let utf8_records = read(utf8.as_bytes().to_vec());
+
+        assert_eq!(legacy_records.len(), 1);
+        assert_eq!(legacy_records, utf8_records);
+        assert_eq!(legacy_records[0].town, "大通西");
+        assert_eq!(legacy_records[0].town_kana, "オオドオリニシ");
+        assert_eq!(legacy_records[0].national_local_government_code, "01101");
+    }
+
+    #[test]
+    fn merge_split_rows_with_repeated_kana() {
+        // 町域名カナは分割されず、各行に同じ値が繰り返されている
+        let records = read_ken_all(concat!(
+            "01224,\"066  \",\"0660005\",\"ﾎｯｶｲﾄﾞｳ\",\"ﾁﾄｾｼ\",\"ｷｮｳﾜ\",\"北海道\",\"千歳市\",\"協和（８８－２、２７１－１０、３４３－２、４０４－１、４２７－\",0,1,0,0,0,0\n",
+            "01224,\"066  \",\"0660005\",\"ﾎｯｶｲﾄﾞｳ\",\"ﾁﾄｾｼ\",\"ｷｮｳﾜ\",\"北海道\",\"千歳市\",\"３、４３１－１２、４４３－６、６０８－２、６４１－８、８１４、８４２－\",0,1,0,0,0,0\n",
+            "01224,\"066  \",\"0660005\",\"ﾎｯｶｲﾄﾞｳ\",\"ﾁﾄｾｼ\",\"ｷｮｳﾜ\",\"北海道\",\"千歳市\",\"５、１１３７－３、１３９２、１６５７、１７５２番地）\",0,1,0,0,0,0\n",
+        ));
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].town, "協和");
+        assert_eq!(records[0].town_kana, "キョウワ");
+        assert_eq!(
+            records[0].annotation.as_ref().unwrap().text,
+            "８８－２、２７１－１０、３４３－２、４０４－１、４２７－３、４３１－１２、４４３－６、６０８－２、６４１－８、８１４、８４２－５、１１３７－３、１３９２、１６５７、１７５２番地"
+        );
+    }
+
+    #[test]
+    fn merge_split_rows_with_split_kana() {
+        // 町域名カナも町域名と同じように複数行に分割されている
+        let records = read_ken_all(concat!(
+            "01409,\"04824\",\"0482402\",\"ﾎｯｶｲﾄﾞｳ\",\"ﾖｲﾁｸﾞﾝﾆｷﾁｮｳ\",\"ｵｵｴ(1ﾁｮｳﾒ､2ﾁｮｳﾒ<651､662､668ﾊﾞﾝﾁ>ｲｶﾞｲ､3ﾁｮｳﾒ5､13-4､\",\"北海道\",\"余市郡仁木町\",\"大江（１丁目、２丁目「６５１、６６２、６６８番地」以外、３丁目５、１３－４、\",1,0,1,0,0,0\n",
+            "01409,\"04824\",\"0482402\",\"ﾎｯｶｲﾄﾞｳ\",\"ﾖｲﾁｸﾞﾝﾆｷﾁｮｳ\",\"20､678､687ﾊﾞﾝﾁ)\",\"北海道\",\"余市郡仁木町\",\"２０、６７８、６８７番地）\",1,0,1,0,0,0\n",
+        ));
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].town, "大江");
+        assert_eq!(records[0].town_kana, "オオエ");
+        assert_eq!(
+            records[0].annotation.as_ref().unwrap().text,
+            "１丁目、２丁目「６５１、６６２、６６８番地」以外、３丁目５、１３－４、２０、６７８、６８７番地"
+        );
+    }
+
+    #[test]
+    fn classify_annotation_chome() {
+        let annotation = parse_town_annotation("１～１９丁目");
+
+        assert_eq!(annotation.chome, vec!["１～１９丁目"]);
+        assert!(annotation.banchi.is_empty());
+        assert!(!annotation.excluded);
+    }
+
+    #[test]
+    fn classify_annotation_banchi_with_numbers() {
+        let annotation = parse_town_annotation("１９５、１９６番地");
+
+        assert_eq!(annotation.banchi, vec!["１９５", "１９６番地"]);
+        assert!(annotation.chome.is_empty());
+        assert!(annotation.others.is_empty());
+        assert!(!annotation.excluded);
+    }
+
+    #[test]
+    fn classify_annotation_floor() {
+        assert_eq!(
+            parse_town_annotation("地階・階層不明").floor.as_deref(),
+            Some("地階・階層不明")
+        );
+        let annotation = parse_town_annotation("１階");
+        assert_eq!(annotation.floor.as_deref(), Some("１階"));
+        assert!(annotation.others.is_empty());
+    }
+
+    #[test]
+    fn classify_annotation_chiwari_and_others() {
+        let annotation = parse_town_annotation("６４地割～６６地割、その他");
+
+        assert_eq!(annotation.others, vec!["６４地割～６６地割", "その他"]);
+        assert!(annotation.chome.is_empty());
+        assert!(annotation.banchi.is_empty());
+        assert!(!annotation.excluded);
+    }
+
+    #[test]
+    fn classify_annotation_excluded_building() {
+        let annotation = parse_town_annotation("次のビルを除く");
+
+        assert!(annotation.excluded);
+        assert_eq!(annotation.others, vec!["次のビル"]);
+        assert_eq!(annotation.text, "次のビルを除く");
+    }
+
+    #[test]
+    fn classify_annotation_excluded_quoted_banchi() {
+        let annotation = parse_town_annotation("「２５１、２５３番地」以外");
+
+        assert!(annotation.excluded);
+        assert_eq!(annotation.banchi, vec!["２５１", "２５３番地"]);
+        assert!(annotation.others.is_empty());
+        assert_eq!(annotation.text, "「２５１、２５３番地」以外");
+    }
+
+    #[test]
+    fn classify_annotation_partially_excluded_chome() {
+        // 一部の丁目の中だけを除外する注記は、注記全体としては除外を表さない
+        let annotation = parse_town_annotation(
+            "１丁目、２丁目「６５１、６６２、６６８番地」以外、３丁目５、１３－４、２０、６７８、６８７番地",
+        );
+
+        assert!(!annotation.excluded);
+        assert_eq!(
+            annotation.chome,
+            vec![
+                "１丁目",
+                "２丁目「６５１、６６２、６６８番地」以外",
+                "３丁目５"
+            ]
+        );
+        assert_eq!(
+            annotation.banchi,
+            vec!["１３－４", "２０", "６７８", "６８７番地"]
+        );
+    }
+
+    #[test]
+    fn merge_stops_at_different_postal_code() {
+        // 閉じカッコが欠けていても、郵便番号が異なる行は連結しない
+        let records = read_ken_all(concat!(
+            "01101,\"060  \",\"0600001\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ｷﾀ1ｼﾞｮｳﾆｼ(1-19ﾁｮｳﾒ\",\"北海道\",\"札幌市中央区\",\"北一条西（１～１９丁目\",0,0,1,0,0,0\n",
+            "01101,\"064  \",\"0640941\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ｱｻﾋｶﾞｵｶ\",\"北海道\",\"札幌市中央区\",\"旭ケ丘\",0,0,1,0,0,0\n",
+        ));
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].town, "北一条西");
+        assert_eq!(records[0].town_kana, "キタ１ジョウニシ");
+        assert_eq!(records[0].annotation.as_ref().unwrap().text, "１～１９丁目");
+        assert_eq!(records[1].postal_code, "0640941");
+        assert_eq!(records[1].town, "旭ケ丘");
+    }
+
+    #[test]
+    fn short_row_is_skipped_as_row_error() {
+        // 列が足りない行は行ごとのエラーとして返し、次の行から読み込みを続ける
+        let rome = RomeJoin::new("".as_bytes(), &KEN_ALL_ROME_SCHEMA);
+        let csv = concat!(
+            "01101,\"060  \",\"0600000\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\"\n",
+            "01101,\"064  \",\"0640941\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ｱｻﾋｶﾞｵｶ\",\"北海道\",\"札幌市中央区\",\"旭ケ丘\",0,0,1,0,0,0\n",
+        );
+        let results =
+            KenAllRecords::new(csv.as_bytes(), KEN_ALL_SCHEMA, Box::new(rome)).collect::<Vec<_>>();
+
+        assert_eq!(results.len(), 2);
+        let err = results[0].as_ref().unwrap_err();
+        assert!(err.is_row_error());
+        assert_eq!(err.row(), Some(1));
+        assert_eq!(results[1].as_ref().unwrap().town, "旭ケ丘");
+    }
+
+    #[test]
+    fn join_rome_in_file_order() {
+        // KEN_ALL_ROMEにしかない住所は読み飛ばし、町域名が一致しない住所には市区町村名までのローマ字表記を設定する
+        let rome = RomeJoin::new(
+            concat!(
+                "\"0640941\",\"北海道\",\"札幌市　中央区\",\"旭ケ丘\",\"HOKKAIDO\",\"SAPPORO SHI CHUO KU\",\"ASAHIGAOKA\"\n",
+                "\"0640942\",\"北海道\",\"札幌市　中央区\",\"伏見町\",\"HOKKAIDO\",\"SAPPORO SHI CHUO KU\",\"FUSHIMICHO\"\n",
+                "\"0640940\",\"北海道\",\"札幌市　中央区\",\"盤渓\",\"HOKKAIDO\",\"SAPPORO SHI CHUO KU\",\"BANKEI\"\n",
+                "\"0640943\",\"北海道\",\"札幌市　中央区\",\"宮ケ丘\",\"HOKKAIDO\",\"SAPPORO SHI CHUO KU\",\"MIYAGAOKA\"\n",
+            )
+            .as_bytes(),
+            &KEN_ALL_ROME_SCHEMA,
+        );
+        let csv = concat!(
+            "01101,\"064  \",\"0640941\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ｱｻﾋｶﾞｵｶ\",\"北海道\",\"札幌市中央区\",\"旭ケ丘\",0,0,1,0,0,0\n",
+            "01101,\"064  \",\"0640942\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ﾌｼﾐ\",\"北海道\",\"札幌市中央区\",\"伏見\",0,0,1,0,0,0\n",
+            "01101,\"064  \",\"0640943\",\"ﾎｯｶｲﾄﾞｳ\",\"ｻｯﾎﾟﾛｼﾁｭｳｵｳｸ\",\"ﾐﾔｶﾞｵｶ\",\"北海道\",\"札幌市中央区\",\"宮ケ丘\",0,0,1,0,0,0\n",
+        );
+        let records = KenAllRecords::new(csv.as_bytes(), KEN_ALL_SCHEMA, Box::new(rome))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].city_roma, "SAPPORO SHI CHUO KU");
+        assert_eq!(records[0].town_roma, "ASAHIGAOKA");
+        assert_eq!(records[1].city_roma, "SAPPORO SHI CHUO KU");
+        assert_eq!(records[1].town_roma, "");
+        assert_eq!(records[2].town_roma, "MIYAGAOKA");
+    }
 }
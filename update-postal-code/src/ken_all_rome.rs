@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Read;
+
+use regex::Regex;
+
+use crate::csv_schema::{CsvRecords, CsvSchema};
+use crate::error::IngestError;
+use crate::ken_all::{bracket_depth, is_unnecessary_town};
+use crate::postal_code_record::PostalCodeRecord;
+
+/// 住所の郵便番号データ(ローマ字)のURL
+pub const KEN_ALL_ROME_URL: &str =
+    "https://www.post.japanpost.jp/zipcode/dl/roman/KEN_ALL_ROME.zip";
+
+// ken_allと並べて読み込む際に先読みして保持するローマ字表記の最大件数
+// KEN_ALL_ROMEはken_allと同じ順に並んでいるため、片方にしかない住所を読み飛ばせる程度あればよい
+const LOOKAHEAD: usize = 1000;
+
+/// 住所レコードにローマ字表記を設定する
+pub trait AttachRoma {
+    /// 郵便番号・市区町村名・町域名が一致するローマ字表記を住所レコードに設定する
+    /// 町域名が一致しない場合は、都道府県名と市区町村名のローマ字表記のみを設定する
+    fn attach(
+        &mut self,
+        postal_code_record_list: &mut [PostalCodeRecord],
+    ) -> Result<(), IngestError>;
+}
+
+/// 都道府県名・市区町村名・町域名のローマ字表記
+#[derive(Debug, Clone)]
+struct Roma {
+    // 結合に使用する郵便番号・市区町村名・町域名
+    postal_code: String,
+    city: String,
+    town: String,
+    prefecture_roma: String,
+    city_roma: String,
+    town_roma: String,
+}
+
+impl Roma {
+    // 住所レコードと郵便番号・市区町村名が一致するか
+    fn is_same_city(&self, record: &PostalCodeRecord) -> bool {
+        self.postal_code == record.postal_code && self.city == record.city
+    }
+
+    // 町域名まで一致すれば町域名のローマ字表記も、市区町村名までであれば都道府県名と市区町村名のみを設定する
+    fn set_to(&self, record: &mut PostalCodeRecord, with_town: bool) {
+        record.prefecture_roma = self.prefecture_roma.clone();
+        record.city_roma = self.city_roma.clone();
+        if with_town {
+            record.town_roma = self.town_roma.clone();
+        }
+    }
+}
+
+/// 一部の郵便番号のKEN_ALL_ROMEの内容を、住所レコードに結合しやすい形で保持する
+/// 月次の差分ファイルのように、ken_allと並び順を揃えて読み込めない場合に使用する
+pub struct RomeTable {
+    // 郵便番号ごとのローマ字表記 (ファイル内の順)
+    by_postal_code: HashMap<String, Vec<Roma>>,
+}
+
+impl AttachRoma for &RomeTable {
+    fn attach(
+        &mut self,
+        postal_code_record_list: &mut [PostalCodeRecord],
+    ) -> Result<(), IngestError> {
+        for record in postal_code_record_list {
+            let roma_list = match self.by_postal_code.get(&record.postal_code) {
+                Some(roma_list) => roma_list,
+                None => continue,
+            };
+            let mut same_city = roma_list.iter().filter(|roma| roma.is_same_city(record));
+            if let Some(roma) = same_city.clone().find(|roma| roma.town == record.town) {
+                roma.set_to(record, true);
+            } else if let Some(roma) = same_city.next() {
+                roma.set_to(record, false);
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// KEN_ALL_ROMEをken_allと並べて先頭から読み込み、読み込んだ住所レコードにローマ字表記を設定する
+/// KEN_ALL_ROMEには全国地方公共団体コードが含まれていないため、郵便番号と市区町村名で市区町村を識別する
+/// 両方のファイルは同じ順に並んでいるため、全件を保持せずに先読みした一部のみを保持する
+pub struct RomeJoin<R: Read> {
+    records: RomaRecords<R>,
+    // 先読みしたローマ字表記 (ファイル内の順)
+    buffer: VecDeque<Roma>,
+    // KEN_ALL_ROMEを最後まで読み込んだ場合にtrue
+    finished: bool,
+}
+
+impl<R: Read> RomeJoin<R> {
+    pub fn new(reader: R, schema: &CsvSchema<KenAllRomeColumns>) -> RomeJoin<R> {
+        RomeJoin {
+            records: RomaRecords::new(reader, schema),
+            buffer: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    // 住所レコードに一つのローマ字表記を設定する
+    fn attach_record(&mut self, record: &mut PostalCodeRecord) -> Result<(), IngestError> {
+        //町域名まで一致するローマ字表記が見つかるまで、先読みの上限まで読み込む
+        loop {
+            let found = self
+                .buffer
+                .iter()
+                .position(|roma| roma.is_same_city(record) && roma.town == record.town);
+            if let Some(index) = found {
+                self.buffer[index].set_to(record, true);
+                //一致したものより前のローマ字表記は、ken_allにない住所のため捨てる
+                self.buffer.drain(..=index);
+                return Ok(());
+            }
+            if self.finished || self.buffer.len() >= LOOKAHEAD {
+                break;
+            }
+
+            match self.records.next() {
+                Some(roma) => self.buffer.push_back(roma?),
+                None => self.finished = true,
+            }
+        }
+
+        //町域名が一致しない場合は、郵便番号と市区町村名が一致するものから都道府県名と市区町村名のみを設定する
+        if let Some(index) = self
+            .buffer
+            .iter()
+            .position(|roma| roma.is_same_city(record))
+        {
+            self.buffer[index].set_to(record, false);
+            //一致したものより前のローマ字表記は、ken_allにない住所のため捨てる
+            self.buffer.drain(..index);
+        }
+
+        return Ok(());
+    }
+}
+
+impl<R: Read> AttachRoma for RomeJoin<R> {
+    fn attach(
+        &mut self,
+        postal_code_record_list: &mut [PostalCodeRecord],
+    ) -> Result<(), IngestError> {
+        for record in postal_code_record_list {
+            self.attach_record(record)?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// KEN_ALL_ROMEの各項目の列の位置
+#[derive(Debug, Clone, Copy)]
+pub struct KenAllRomeColumns {
+    pub postal_code: usize,
+    pub city: usize,
+    pub town: usize,
+    pub prefecture_roma: usize,
+    pub city_roma: usize,
+    pub town_roma: usize,
+}
+
+/// KEN_ALL_ROME.zipの形式 (ヘッダー行はない)
+pub const KEN_ALL_ROME_SCHEMA: CsvSchema<KenAllRomeColumns> = CsvSchema {
+    file: "ken_all_rome",
+    has_headers: false,
+    delimiter: b',',
+    quoting: true,
+    column_count: 7,
+    columns: KenAllRomeColumns {
+        postal_code: 0,
+        city: 2,
+        town: 3,
+        prefecture_roma: 4,
+        city_roma: 5,
+        town_roma: 6,
+    },
+};
+
+/// KEN_ALL_ROMEのCSVを一行ずつ読み込み、ken_allと同じように正規化したローマ字表記を順に返すイテレーター
+/// 複数行に分割された町域名は、ken_allと同じく閉じカッコまでの同じ郵便番号の行を連結してから正規化する
+struct RomaRecords<R: Read> {
+    schema: CsvSchema<KenAllRomeColumns>,
+    records: CsvRecords<R>,
+    //分割行の続きとして読み込んだが、郵便番号が異なっていたため次の住所の先頭行とする行
+    peeked: Option<Roma>,
+    //（...）と(...)にマッチする正規表現
+    zenkaku_bracket_regexp: Regex,
+    hankaku_bracket_regexp: Regex,
+}
+
+impl<R: Read> RomaRecords<R> {
+    fn new(reader: R, schema: &CsvSchema<KenAllRomeColumns>) -> RomaRecords<R> {
+        RomaRecords {
+            schema: *schema,
+            records: schema.records(reader),
+            peeked: None,
+            zenkaku_bracket_regexp: Regex::new(r"（.*?）").unwrap(),
+            hankaku_bracket_regexp: Regex::new(r"\(.*?\)").unwrap(),
+        }
+    }
+
+    // 次の行を読み込む (最後まで読み込んでいればNoneを返す)
+    fn read_row(&mut self) -> Result<Option<Roma>, IngestError> {
+        if let Some(roma) = self.peeked.take() {
+            return Ok(Some(roma));
+        }
+
+        let columns = &self.schema.columns;
+        loop {
+            let record = match self.records.next() {
+                Some(Ok(record)) => record,
+                //ローマ字表記は住所レコードに付加するだけなので、不正な行は結合せずに読み飛ばす
+                Some(Err(err)) if err.is_row_error() => {
+                    tracing::warn!(error = %err, "skip invalid ken_all_rome row");
+                    continue;
+                }
+                Some(Err(err)) => return Err(err),
+                None => return Ok(None),
+            };
+
+            let postal_code = &record[columns.postal_code]; //郵便番号 半角数字
+
+            //市区町村名 (政令指定都市の区や郡の前に全角スペースが入っているため、ken_allに合わせて取り除く)
+            let city = record[columns.city].replace('　', "");
+            let town = &record[columns.town]; //町域名
+            let prefecture_roma = &record[columns.prefecture_roma]; //都道府県名ローマ字
+            let city_roma = &record[columns.city_roma]; //市区町村名ローマ字
+            let town_roma = &record[columns.town_roma]; //町域名ローマ字
+
+            return Ok(Some(Roma {
+                postal_code: postal_code.to_owned(),
+                city,
+                town: town.to_owned(),
+                prefecture_roma: prefecture_roma.trim().to_owned(),
+                city_roma: city_roma.trim().to_owned(),
+                town_roma: town_roma.to_owned(),
+            }));
+        }
+    }
+
+    // 一つの住所を表す行(複数行に分割されていればそのすべての行)を読み込んで、正規化したローマ字表記を返す
+    fn read_chunk(&mut self) -> Result<Option<Roma>, IngestError> {
+        let mut roma = match self.read_row()? {
+            Some(roma) => roma,
+            None => return Ok(None),
+        };
+
+        //閉じていない開きカッコを含む場合は、カッコが閉じるまで同じ郵便番号の行を続けて連結する
+        let mut depth = bracket_depth(&roma.town);
+        while depth > 0 {
+            let row = match self.read_row()? {
+                Some(row) => row,
+                None => break,
+            };
+            //郵便番号が異なる行は、閉じカッコが欠けた行の後に続く別の住所として扱う
+            if row.postal_code != roma.postal_code {
+                self.peeked = Some(row);
+                break;
+            }
+            depth += bracket_depth(&row.town);
+            roma.town.push_str(&row.town);
+            roma.town_roma.push_str(&row.town_roma);
+        }
+
+        //ken_allの正規化(record_normalize)と同じように町域名を正規化して、結合できるようにする
+        if is_unnecessary_town(&roma.town) {
+            roma.town = "".to_string();
+            roma.town_roma = "".to_string();
+        }
+        roma.town = self
+            .zenkaku_bracket_regexp
+            .replace_all(&roma.town, "")
+            .to_string();
+        roma.town_roma = self
+            .hankaku_bracket_regexp
+            .replace_all(&roma.town_roma, "")
+            .trim()
+            .to_string();
+        //閉じカッコが欠けている場合は、開きカッコより後ろを取り除く
+        if let Some(start_index) = roma.town.find('（') {
+            roma.town.truncate(start_index);
+            if let Some(roma_start_index) = roma.town_roma.find('(') {
+                roma.town_roma = roma.town_roma[..roma_start_index].trim().to_string();
+            }
+        }
+
+        return Ok(Some(roma));
+    }
+}
+
+impl<R: Read> Iterator for RomaRecords<R> {
+    type Item = Result<Roma, IngestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.read_chunk().transpose();
+    }
+}
+
+/// KEN_ALL_ROMEのCSVを読み込んで、指定された郵便番号のみのローマ字表記の対応表を作成する
+pub fn ken_all_rome_table<R: Read>(
+    reader: R,
+    schema: &CsvSchema<KenAllRomeColumns>,
+    postal_codes: &HashSet<String>,
+) -> Result<RomeTable, IngestError> {
+    let mut table = RomeTable {
+        by_postal_code: HashMap::new(),
+    };
+    for roma in RomaRecords::new(reader, schema) {
+        let roma = roma?;
+        if !postal_codes.contains(&roma.postal_code) {
+            continue;
+        }
+        table
+            .by_postal_code
+            .entry(roma.postal_code.clone())
+            .or_default()
+            .push(roma);
+    }
+
+    return Ok(table);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // KEN_ALL_ROMEの形式のCSVを読み込んで、正規化後のローマ字表記を取得する
+    fn read_rome(csv: &str) -> Vec<Roma> {
+        return RomaRecords::new(csv.as_bytes(), &KEN_ALL_ROME_SCHEMA)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+    }
+
+    #[test]
+    fn merge_split_rows() {
+        let romas = read_rome(concat!(
+            "\"0660005\",\"北海道\",\"千歳市\",\"協和（８８－２、２７１－１０、\",\"HOKKAIDO\",\"CHITOSE SHI\",\"KYOWA(88-2,271-10,\"\n",
+            "\"0660005\",\"北海道\",\"千歳市\",\"１７５２番地）\",\"HOKKAIDO\",\"CHITOSE SHI\",\"1752BANCHI)\"\n",
+            "\"0660006\",\"北海道\",\"千歳市\",\"釜加\",\"HOKKAIDO\",\"CHITOSE SHI\",\"KAMAKA\"\n",
+        ));
+
+        assert_eq!(romas.len(), 2);
+        assert_eq!(romas[0].town, "協和");
+        assert_eq!(romas[0].town_roma, "KYOWA");
+        assert_eq!(romas[1].town, "釜加");
+    }
+
+    #[test]
+    fn merge_stops_at_different_postal_code() {
+        // 閉じカッコが欠けていても、郵便番号が異なる行は連結しない
+        let romas = read_rome(concat!(
+            "\"0600001\",\"北海道\",\"札幌市　中央区\",\"北一条西（１～１９丁目\",\"HOKKAIDO\",\"SAPPORO SHI CHUO KU\",\"KITA1-JONISHI(1-19-CHOME\"\n",
+            "\"0640941\",\"北海道\",\"札幌市　中央区\",\"旭ケ丘\",\"HOKKAIDO\",\"SAPPORO SHI CHUO KU\",\"ASAHIGAOKA\"\n",
+        ));
+
+        assert_eq!(romas.len(), 2);
+        assert_eq!(romas[0].town, "北一条西");
+        assert_eq!(romas[0].town_roma, "KITA1-JONISHI");
+        assert_eq!(romas[1].postal_code, "0640941");
+        assert_eq!(romas[1].town, "旭ケ丘");
+    }
+}
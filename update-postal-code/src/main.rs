@@ -1,87 +1,368 @@
+mod batch_get;
+mod batch_write;
+mod city_name;
+mod csv_schema;
+mod dry_run;
+mod error;
+mod history;
+mod jigyosyo;
+mod kana;
 mod ken_all;
+mod ken_all_rome;
 mod postal_code_record;
+mod source;
+mod town_name;
+mod validation;
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use aws_sdk_dynamodb::types::{PutRequest, WriteRequest};
+use aws_sdk_dynamodb::types::{DeleteRequest, PutRequest, WriteRequest};
 use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 
-use crate::postal_code_record::PostalCodeRecord;
+use crate::batch_write::{BatchWriteConfig, BatchWriter};
+use crate::dry_run::DryRunReport;
+use crate::error::IngestError;
+use crate::ken_all::{ContentHasher, SourceFormat};
+use crate::postal_code_record::{CityName, PostalCodeRecord, RecordType};
+use crate::source::{CacheValidator, DataSources, FetchedSources, Source};
+use crate::validation::{ValidationReport, ValidationThresholds, Validator};
 
 // コンテンツ全体に対するハッシュ値を保存するキー (national_local_government_codeと絶対に被らない適当な文字列であればよい)
 const HASH_ITEM_KEY: &str = "#hash#";
+// 適用済みの差分ファイルの年月を保存するキーのプレフィックス
+const DIFF_ITEM_KEY_PREFIX: &str = "#diff#";
+// ダウンロードしたファイルのETag/Last-Modifiedを保存するキーのプレフィックス
+const SOURCE_ITEM_KEY_PREFIX: &str = "#source#";
+// 更新時に削除してよい郵便番号の割合のデフォルト値 (これを超える場合は元データの異常とみなして更新を中止する)
+const DEFAULT_MAX_DELETE_RATIO: f64 = 0.05;
+// Lambdaのタイムアウトまでの残り時間がこれを下回ったら処理を中断する (秒)
+const DEFAULT_DEADLINE_MARGIN_SECONDS: u64 = 60;
+// チェックポイントごとに書き込む項目数の目安
+// 市区町村ごとに書き込みの完了を待つと並行して書き込めないため、この件数に達するまで複数の市区町村をまとめて書き込む
+const CHECKPOINT_ITEM_COUNT: usize = 1000;
+
+// 一つの市区町村について書き込む項目と削除する郵便番号
+struct MunicipalityWrite {
+    national_local_government_code: String,
+    // 市区町村の住所レコードに変更があればtrue (書き込みが完了したらハッシュ値を書き込む)
+    is_changed: bool,
+    // 書き込む項目とその項目を書き込む市区町村のペア
+    put_item_list: Vec<(String, HashMap<String, AttributeValue>)>,
+    deleted_postal_codes: Vec<String>,
+}
+
+// ハッシュテーブルに保存されている項目
+#[derive(Clone, Default)]
+struct HashItem {
+    hash: String,
+    // national_local_government_codeの項目に含まれる郵便番号
+    postal_codes: Vec<String>,
+    // コンテンツ全体の項目に含まれるnational_local_government_code
+    national_local_government_codes: Vec<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Default)]
+struct RequestData {
+    // 月次の差分ファイルを適用する場合は対象の年月をYYMM形式で指定する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    diff_month: Option<String>,
+    // trueの場合はDynamoDBへの書き込みを行わずに、更新予定の差分をレポートとして返却する
+    #[serde(default)]
+    dry_run: bool,
+    // 住所が変更された項目に記録するデータセットのバージョン (YYYY-MM-DD)
+    // 指定がなければ実行日を使用する。中断した処理の続きでは、中断前と同じバージョンを引き継ぐ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dataset_version: Option<String>,
+    // 各データの読み込み元 (URL、ローカルのzipファイル、ローカルのCSVファイル、「-」で標準入力)
+    // 指定がなければ環境変数(KEN_ALL_SOURCE, JIGYOSYO_SOURCE, KEN_ALL_ROME_SOURCE)、日本郵便のURLの順に使用する
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ken_all_source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    jigyosyo_source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ken_all_rome_source: Option<String>,
+}
 
 #[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
 struct ResponseData {
     code: usize,
     count: usize,
+    // 再試行しても書き込めなかった件数
+    failed_count: usize,
     message: String,
+    // 郵便番号データの取り込みに失敗した場合のみエラーの詳細を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorReport>,
+    // ドライランの場合のみ更新予定の差分を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run_report: Option<DryRunReport>,
+    // 住所レコードを検証した場合のみ、検証ルールごとの違反の件数と例を持つ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validation_report: Option<ValidationReport>,
+    // タイムアウト前に中断した場合に、続きを処理するために次回の実行で渡すイベント
+    #[serde(skip_serializing_if = "Option::is_none")]
+    continuation: Option<RequestData>,
+}
+
+/// 郵便番号データの取り込みに失敗した場合のエラーの詳細
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug)]
+struct ErrorReport {
+    // エラーの種類 (download, http_status, io, zip, encoding, csv, invalid_row, not_sorted, validation, delete_ratio_exceeded)
+    kind: String,
+    // CSVの行のエラーの場合は、エラーが発生したファイルと行番号
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<u64>,
+}
+
+impl ResponseData {
+    /// 書き込み件数・エラーの詳細・レポートを持たないレスポンスを作成する
+    fn new(code: usize, message: String) -> ResponseData {
+        ResponseData {
+            code,
+            count: 0,
+            failed_count: 0,
+            message,
+            error: None,
+            dry_run_report: None,
+            validation_report: None,
+            continuation: None,
+        }
+    }
+
+    /// 取り込みに失敗した場合のレスポンスを作成する
+    /// 失敗したレスポンスを返すため、EventBridgeによる再試行は行われない
+    fn ingest_error(err: IngestError) -> ResponseData {
+        tracing::error!(error = %err, "Failed to ingest postal code data");
+        ResponseData {
+            error: Some(ErrorReport {
+                kind: err.kind().to_string(),
+                file: err.file().map(|file| file.to_string()),
+                row: err.row(),
+            }),
+            ..ResponseData::new(1, err.to_string())
+        }
+    }
 }
 
 async fn function_handler(
     client: &Client,
-    _event: LambdaEvent<serde_json::Value>,
+    event: LambdaEvent<serde_json::Value>,
 ) -> Result<serde_json::Value, Error> {
     tracing::info!("Start handler");
 
     // 環境変数から必要なDynamoDBのテーブル名を取得
     let table_name = env::var("POSTAL_CODE_TABLE").expect("POSTAL_CODE_TABLE not set");
     let hash_table_name = env::var("HASH_TABLE").expect("POSTAL_CODE_TABLE not set");
+    let history_table_name = env::var("HISTORY_TABLE").expect("HISTORY_TABLE not set");
+    // 取り込むken_allのファイル形式 (未指定の場合は従来のShift-JIS版)
+    let source_format = match env::var("KEN_ALL_FORMAT") {
+        Ok(name) => SourceFormat::from_name(&name).expect("KEN_ALL_FORMAT is invalid"),
+        Err(_) => SourceFormat::Legacy,
+    };
+
+    // batch_write_itemの再試行を含めた試行回数の上限と同時実行数
+    let batch_write_config = BatchWriteConfig {
+        max_attempts: match env::var("BATCH_WRITE_MAX_ATTEMPTS") {
+            Ok(attempts) => attempts
+                .parse::<usize>()
+                .expect("BATCH_WRITE_MAX_ATTEMPTS is invalid"),
+            Err(_) => crate::batch_write::DEFAULT_MAX_ATTEMPTS,
+        },
+        concurrency: match env::var("BATCH_WRITE_CONCURRENCY") {
+            Ok(concurrency) => concurrency
+                .parse::<usize>()
+                .expect("BATCH_WRITE_CONCURRENCY is invalid"),
+            Err(_) => crate::batch_write::DEFAULT_CONCURRENCY,
+        },
+    };
+
+    // Lambdaのタイムアウトの何ミリ秒前に処理を中断するか
+    let deadline_margin_millis = match env::var("DEADLINE_MARGIN_SECONDS") {
+        Ok(seconds) => seconds
+            .parse::<u64>()
+            .expect("DEADLINE_MARGIN_SECONDS is invalid"),
+        Err(_) => DEFAULT_DEADLINE_MARGIN_SECONDS,
+    } * 1000;
+
+    // 住所レコードの検証ルールごとの違反の許容件数
+    let validation_thresholds = match ValidationThresholds::from_env() {
+        Ok(validation_thresholds) => validation_thresholds,
+        Err(err) => return Ok(serde_json::json!(ResponseData::ingest_error(err))),
+    };
+
+    // イベントの内容を取得 (EventBridgeからの定期実行など、指定がなければ全件の更新を行う)
+    let request = if event.payload.is_null() {
+        RequestData::default()
+    } else {
+        serde_json::from_value::<RequestData>(event.payload)?
+    };
+
+    // 住所が変更された項目に記録するデータセットのバージョンと、履歴のキーに使用する実行日時
+    let run_millis = now_millis();
+    let update_version = crate::history::UpdateVersion {
+        dataset_version: match &request.dataset_version {
+            Some(dataset_version) => dataset_version.clone(),
+            None => crate::history::dataset_version_from_millis(run_millis),
+        },
+        superseded_at: crate::history::timestamp_from_millis(run_millis),
+    };
+
+    // 各データの読み込み元を決定する
+    let sources = DataSources {
+        ken_all: Source::resolve(
+            request.ken_all_source.as_deref(),
+            "KEN_ALL_SOURCE",
+            source_format.url(),
+        ),
+        jigyosyo: Source::resolve(
+            request.jigyosyo_source.as_deref(),
+            "JIGYOSYO_SOURCE",
+            crate::jigyosyo::JIGYOSYO_URL,
+        ),
+        ken_all_rome: Source::resolve(
+            request.ken_all_rome_source.as_deref(),
+            "KEN_ALL_ROME_SOURCE",
+            crate::ken_all_rome::KEN_ALL_ROME_URL,
+        ),
+    };
+    tracing::info!(sources = ?sources, "Data sources");
+
+    // 差分月が指定されていれば、差分ファイルのみを適用する
+    if let Some(diff_month) = request.diff_month {
+        //差分ファイルは日本郵便のURLから取得するため、ken_allの読み込み元の指定とは併用できない
+        if request.ken_all_source.is_some() {
+            return Ok(serde_json::json!(ResponseData::new(
+                1,
+                "ken_all_source is not supported with diff_month".to_string()
+            )));
+        }
+        if request.dry_run {
+            return Ok(serde_json::json!(ResponseData::new(
+                1,
+                "dry_run is not supported with diff_month".to_string()
+            )));
+        }
+
+        let tables = TableNames {
+            postal_code: table_name,
+            hash: hash_table_name,
+            history: history_table_name,
+        };
+        let response_data = diff_update(
+            client,
+            &tables,
+            source_format,
+            &diff_month,
+            &sources.ken_all_rome,
+            batch_write_config,
+            &update_version,
+        )
+        .await?;
+
+        return Ok(serde_json::json!(response_data));
+    }
+
+    // 前回ダウンロードしたファイルの検証子を取得する
+    // 検証子はすべての更新が完了した場合のみ保存するため、中断した処理の続きでも条件付きリクエストで更新を検知できる
+    let previous_validators = get_source_validators(client, &hash_table_name).await?;
+
+    let started_at = Instant::now();
+    tracing::info!("start fetch sources");
+    let FetchedSources {
+        contents,
+        validators,
+    } = match crate::source::fetch_all(&sources, &previous_validators) {
+        Ok(Some(fetched)) => fetched,
+        Err(err) => return Ok(serde_json::json!(ResponseData::ingest_error(err))),
+        Ok(None) => {
+            //すべてのファイルが前回から更新されていなければ、何もせずに終了する
+            tracing::info!("sources are not modified");
+            return Ok(serde_json::json!(ResponseData::new(
+                0,
+                "no change".to_string()
+            )));
+        }
+    };
+    tracing::info!(
+        elapsed_millis = started_at.elapsed().as_millis() as u64,
+        "end fetch sources"
+    );
 
     tracing::info!("start ken_all");
-    let ken_all_data = crate::ken_all::ken_all_records();
-    tracing::info!("end ken_all");
+    //ken_allは市区町村ごとに読み込みながら処理し、すべての住所レコードを同時にメモリに保持しないようにする
+    //1回目の読み込みでは、市区町村ごとのハッシュ値と含まれる郵便番号のみを集計する
+    //変更のあった郵便番号の項目は、2回目の読み込みで作成して書き込む
+    let mut hasher = ContentHasher::default();
+    //national_local_government_codeとハッシュ値のペアを格納するマップ
+    let mut national_local_government_code_to_hash = BTreeMap::<String, String>::new();
+    //national_local_government_codeごとに含まれる郵便番号 (消滅した郵便番号の検出に使用する)
+    let mut national_local_government_code_to_postal_codes = HashMap::<String, Vec<String>>::new();
+    //郵便番号ごとに、その郵便番号を含むnational_local_government_codeのリスト (コードの順)
+    let mut postal_code_to_codes = HashMap::<String, Vec<String>>::new();
+    //書き込む前に住所レコードを検証し、不正なデータを公開しないようにする
+    let mut validator = Validator::new(validation_thresholds);
+    let groups = match crate::ken_all::municipality_groups(&contents) {
+        Ok(groups) => groups,
+        Err(err) => return Ok(serde_json::json!(ResponseData::ingest_error(err))),
+    };
+    for group in groups {
+        let (national_local_government_code, records) = match group {
+            Ok(group) => group,
+            //形式が不正な行は読み飛ばし、行の形式の違反として許容件数まで許容する
+            Err(err) if err.is_row_error() => {
+                validator.check_row_error(&err);
+                continue;
+            }
+            Err(err) => return Ok(serde_json::json!(ResponseData::ingest_error(err))),
+        };
 
-    tracing::info!("grouping ken_all");
-    //住所データを郵便番号でグルーピングします
-    let mut postal_code_to_records = HashMap::<String, Vec<PostalCodeRecord>>::new();
-    for (_, records) in ken_all_data.grouped_postal_code_record_list {
+        let hash = hasher.group_hash(&records);
+
+        //市区町村内の住所レコードは郵便番号の順に並んでいる
+        let mut postal_codes = Vec::<String>::new();
         for record in records {
-            //郵便番号をキーにして、同じ郵便番号を持つデータを配列にまとめます
-            postal_code_to_records
-                .entry(record.postal_code.clone())
+            validator.check(&record);
+            if postal_codes.last() != Some(&record.postal_code) {
+                postal_codes.push(record.postal_code);
+            }
+        }
+        for postal_code in &postal_codes {
+            postal_code_to_codes
+                .entry(postal_code.clone())
                 .or_default()
-                .push(record);
+                .push(national_local_government_code.clone());
         }
+
+        national_local_government_code_to_hash.insert(national_local_government_code.clone(), hash);
+        national_local_government_code_to_postal_codes
+            .insert(national_local_government_code, postal_codes);
     }
-    tracing::info!("end grouping ken_all");
-
-    tracing::info!("marge ken_all");
-    //同じ郵便番号を持つデータの情報を統合します
-    let mut result = Vec::<PostalCodeRecord>::new();
-    for (_, mut records) in postal_code_to_records {
-        // データが一つしなかったら
-        if records.len() == 1 {
-            //そのまま結果リストにデータを移します
-            result.push(records.remove(0));
-        } else {
-            //複数データを持っている場合は、異なる値を持つ部分をクリアします
-            let mut item = records.remove(0);
-            for other in &records {
-                // 内容が異なれば
-                if item.town != "" && item.town != other.town {
-                    item.town = "".to_string();
-                    item.town_kana = "".to_string();
-                }
-                if item.city != "" && item.city != other.city {
-                    item.city = "".to_string();
-                    item.city_kana = "".to_string();
-                }
-                if item.prefecture != "" && item.prefecture != other.prefecture {
-                    item.prefecture = "".to_string();
-                    item.prefecture_kana = "".to_string();
-                }
-            }
-            //内容をチェックした後のデータを結果リストに映します
-            result.push(item);
-        }
+    let all_contents_hash = hasher.finish();
+    tracing::info!(
+        elapsed_millis = started_at.elapsed().as_millis() as u64,
+        "end ken_all"
+    );
+
+    //違反の件数が許容件数を超えていれば、元データの異常とみなして更新を中止する
+    let validation_report = validator.finish();
+    if validation_report.failed {
+        let err = IngestError::Validation {
+            rules: validation_report.failed_rules(),
+        };
+        return Ok(serde_json::json!(ResponseData {
+            validation_report: Some(validation_report),
+            ..ResponseData::ingest_error(err)
+        }));
     }
-    tracing::info!("end marge ken_all");
 
     // dynamoDBから取得したハッシュ値をキャッシュする
-    let mut cache = HashMap::<String, String>::new();
+    let mut cache = HashMap::<String, HashItem>::new();
 
     // 全体の変更検知のためにハッシュ値を取得
     let contents_changed = is_hash_change(
@@ -89,156 +370,1087 @@ async fn function_handler(
         hash_table_name.clone(),
         &mut cache,
         HASH_ITEM_KEY.to_string(),
-        &ken_all_data.all_contents_hash,
+        &all_contents_hash,
     )
     .await?;
 
     let mut count = 0;
+    let mut failed_count = 0;
+    //タイムアウト前に中断した場合に、中断したnational_local_government_code
+    //次回の実行ではハッシュ値を書き込み済みの市区町村が変更なしと判定されるため、先頭から読み直して残りを処理する
+    let mut continuation: Option<String> = None;
+    //ドライランの場合に返す、更新予定の差分のレポート
+    let mut report = DryRunReport::default();
     //コンテンツに変更がある場合は
     if contents_changed {
-        // DynamoDBにデータを書き込みます
-        let mut requests = Vec::<WriteRequest>::new();
+        //変更のあったnational_local_government_codeを調べる
+        let national_local_government_code_list = national_local_government_code_to_hash
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
 
-        for record in result {
-            //対象のレコードは変更があったレコードか？
-            let changed = is_hash_change(
+        let mut changed_national_local_government_codes = HashSet::<String>::new();
+        for national_local_government_code in &national_local_government_code_list {
+            if is_hash_change(
                 client,
                 hash_table_name.clone(),
                 &mut cache,
-                record.national_local_government_code.clone(),
-                ken_all_data
-                    .national_local_government_code_to_hash
-                    .get(&record.national_local_government_code)
+                national_local_government_code.clone(),
+                national_local_government_code_to_hash
+                    .get(national_local_government_code)
                     .unwrap(),
             )
+            .await?
+            {
+                changed_national_local_government_codes
+                    .insert(national_local_government_code.clone());
+            }
+        }
+
+        //前回の更新時には存在したが、今回のデータには存在しないnational_local_government_code
+        let vanished_national_local_government_codes = cache
+            .get(HASH_ITEM_KEY)
+            .unwrap()
+            .national_local_government_codes
+            .iter()
+            .filter(|code| !national_local_government_code_to_hash.contains_key(*code))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        //変更のあった郵便番号について、その項目を書き込む市区町村を決める
+        //郵便番号に含まれる住所のうち、最初に見つかった変更のあった市区町村の更新時に書き込む
+        let mut postal_code_to_owner = HashMap::<String, String>::new();
+        //市区町村ごとの、まだ書き込んでいない項目の数
+        let mut owner_to_remaining = HashMap::<String, usize>::new();
+        for (postal_code, national_local_government_codes) in &postal_code_to_codes {
+            if let Some(owner) = national_local_government_codes
+                .iter()
+                .find(|code| changed_national_local_government_codes.contains(*code))
+            {
+                postal_code_to_owner.insert(postal_code.clone(), owner.clone());
+                *owner_to_remaining.entry(owner.clone()).or_default() += 1;
+            }
+        }
+
+        //変更のあった(または存在しなくなった)市区町村に前回含まれていた郵便番号は、その市区町村の住所が項目に残っている
+        //今回のデータのどこにも存在しない郵便番号は削除し、存在する郵便番号は変更のない市区町村にのみ含まれていても書き直す
+        let mut previous_postal_code_count = 0;
+        //削除対象の郵便番号とその郵便番号が含まれていたnational_local_government_codeのペアのリスト
+        let mut deleted_postal_code_list = Vec::<(String, String)>::new();
+        let mut deleted_postal_codes = HashSet::<String>::new();
+        for national_local_government_code in national_local_government_code_list
+            .iter()
+            .chain(vanished_national_local_government_codes.iter())
+        {
+            let hash_item = get_hash_item(
+                client,
+                hash_table_name.clone(),
+                &mut cache,
+                national_local_government_code.clone(),
+            )
             .await?;
+            previous_postal_code_count += hash_item.postal_codes.len();
 
-            // 変更のあったレコードなら
-            if changed {
-                // DynamoDBに住所情報を書き込む
-                let put_request = PutRequest::builder()
-                    .item("postal_code", AttributeValue::S(record.postal_code))
-                    .item("prefecture", AttributeValue::S(record.prefecture))
-                    .item("prefecture_kana", AttributeValue::S(record.prefecture_kana))
-                    .item("city", AttributeValue::S(record.city))
-                    .item("city_kana", AttributeValue::S(record.city_kana))
-                    .item("town", AttributeValue::S(record.town))
-                    .item("town_kana", AttributeValue::S(record.town_kana))
-                    .build();
+            //変更のない市区町村の住所は項目に正しく書き込まれている
+            if !changed_national_local_government_codes.contains(national_local_government_code)
+                && !vanished_national_local_government_codes
+                    .contains(national_local_government_code)
+            {
+                continue;
+            }
 
-                let req = WriteRequest::builder().put_request(put_request).build();
+            for postal_code in hash_item.postal_codes {
+                if postal_code_to_codes.contains_key(&postal_code) {
+                    //前回の住所を取り除くために、この市区町村の更新として書き直す
+                    if let Entry::Vacant(entry) = postal_code_to_owner.entry(postal_code) {
+                        entry.insert(national_local_government_code.clone());
+                        *owner_to_remaining
+                            .entry(national_local_government_code.clone())
+                            .or_default() += 1;
+                    }
+                } else if deleted_postal_codes.insert(postal_code.clone()) {
+                    deleted_postal_code_list
+                        .push((national_local_government_code.clone(), postal_code));
+                }
+            }
+        }
 
-                requests.push(req);
+        //削除件数が多すぎる場合は、元データの異常の可能性があるため更新を中止する
+        let max_delete_ratio = match env::var("MAX_DELETE_RATIO") {
+            Ok(ratio) => ratio.parse::<f64>().expect("MAX_DELETE_RATIO is invalid"),
+            Err(_) => DEFAULT_MAX_DELETE_RATIO,
+        };
+        let delete_ratio_exceeded = previous_postal_code_count > 0
+            && deleted_postal_code_list.len() as f64 / previous_postal_code_count as f64
+                > max_delete_ratio;
 
-                // 大きなデータを送信するとエラーになるため適当な数ごとにバッチリクエストを実行する
-                if requests.len() == 25 {
-                    count += requests.len();
+        // ドライランであれば、中止せずに差分のレポートに記録する
+        if delete_ratio_exceeded && !request.dry_run {
+            let err = IngestError::DeleteRatioExceeded {
+                deleted: deleted_postal_code_list.len(),
+                previous: previous_postal_code_count,
+                max_ratio: max_delete_ratio,
+            };
+            return Ok(serde_json::json!(ResponseData {
+                validation_report: Some(validation_report),
+                ..ResponseData::ingest_error(err)
+            }));
+        }
+        report.delete_ratio_exceeded = delete_ratio_exceeded;
 
-                    send_batch_write_item(client, table_name.to_owned(), requests.clone()).await?;
+        // 市区町村ごとに削除する郵便番号をまとめる
+        let mut national_local_government_code_to_deleted = BTreeMap::<String, Vec<String>>::new();
+        for (national_local_government_code, postal_code) in deleted_postal_code_list {
+            if request.dry_run {
+                report.add_delete(&national_local_government_code, &postal_code);
+            }
+            national_local_government_code_to_deleted
+                .entry(national_local_government_code)
+                .or_default()
+                .push(postal_code);
+        }
+
+        // DynamoDBにデータを書き込みます
+        // ken_allを先頭から読み直し、郵便番号の項目はその郵便番号を含む最後の市区町村を読み込んで住所が揃った時点で書き込む
+        // 市区町村ごとに、その市区町村が書き込む項目がすべて書き込まれたら、その市区町村のハッシュ値を書き込んでチェックポイントとする
+        // 途中で中断しても、ハッシュ値を書き込み済みの市区町村は次回の実行時に変更なしと判定される
+        let mut writer = BatchWriter::new(client, table_name.clone(), batch_write_config);
+        let mut hash_writer = BatchWriter::new(client, hash_table_name.clone(), batch_write_config);
+        // 住所が変更された項目の変更前の住所を書き込む
+        let mut history_writer =
+            BatchWriter::new(client, history_table_name.clone(), batch_write_config);
+
+        //住所が揃うまで、郵便番号ごとの住所レコードを溜めておく
+        let mut pending_records = HashMap::<String, Vec<PostalCodeRecord>>::new();
+        //処理済みで、書き込む項目がすべて書き込まれるのを待っている市区町村
+        let mut waiting_owners = BTreeSet::<String>::new();
+        //書き込めなかった項目がある市区町村 (存在しなくなった市区町村のハッシュ値を削除しないようにする)
+        let mut failed_owners = HashSet::<String>::new();
+        //まだ書き込んでいない市区町村の書き込み内容 (チェックポイントの間隔ごとにまとめて書き込む)
+        let mut window = Vec::<MunicipalityWrite>::new();
+        let mut window_item_count = 0;
 
-                    requests.clear();
+        let groups = match crate::ken_all::municipality_groups(&contents) {
+            Ok(groups) => groups,
+            Err(err) => return Ok(serde_json::json!(ResponseData::ingest_error(err))),
+        };
+        //最後の市区町村の後に、溜まっている書き込み内容を書き込むための終端を加える
+        for group in groups.map(Some).chain(std::iter::once(None)) {
+            let is_last_group = group.is_none();
+            if let Some(group) = group {
+                let (national_local_government_code, records) = match group {
+                    Ok(group) => group,
+                    //不正な行は1回目の読み込みで検証済みのため、読み飛ばす
+                    Err(err) if err.is_row_error() => continue,
+                    Err(err) => return Ok(serde_json::json!(ResponseData::ingest_error(err))),
+                };
+
+                //変更のある郵便番号を含まない市区町村は読み飛ばす
+                let is_changed = changed_national_local_government_codes
+                    .contains(&national_local_government_code);
+                if !is_changed
+                    && !records
+                        .iter()
+                        .any(|record| postal_code_to_owner.contains_key(&record.postal_code))
+                {
+                    continue;
+                }
+
+                //Lambdaのタイムアウトが近づいていたら、残りを次回の実行に引き継いで終了する
+                //チェックポイントの途中では中断せず、新しいチェックポイントを始める前にのみ確認する
+                let remaining_millis = event.context.deadline as i64 - now_millis() as i64;
+                if !request.dry_run
+                    && window.is_empty()
+                    && remaining_millis < deadline_margin_millis as i64
+                {
+                    tracing::info!(
+                        national_local_government_code = national_local_government_code,
+                        "suspend update before timeout"
+                    );
+                    continuation = Some(national_local_government_code);
+                    break;
+                }
+
+                //郵便番号をキーにして、同じ郵便番号を持つデータを配列にまとめます
+                //この市区町村が郵便番号を含む最後の市区町村であれば、その郵便番号の住所が揃う
+                let mut completed_postal_codes = Vec::<String>::new();
+                for record in records {
+                    if !postal_code_to_owner.contains_key(&record.postal_code) {
+                        continue;
+                    }
+
+                    let is_last = postal_code_to_codes
+                        .get(&record.postal_code)
+                        .and_then(|codes| codes.last())
+                        == Some(&national_local_government_code);
+                    if is_last && completed_postal_codes.last() != Some(&record.postal_code) {
+                        completed_postal_codes.push(record.postal_code.clone());
+                    }
+
+                    //住所が全く同じデータは一つにまとめます
+                    let postal_code_records = pending_records
+                        .entry(record.postal_code.clone())
+                        .or_default();
+                    if !postal_code_records
+                        .iter()
+                        .any(|other| is_same_address(&record, other))
+                    {
+                        postal_code_records.push(record);
+                    }
+                }
+
+                // 住所が揃った郵便番号について、書き込む項目とその項目を書き込む市区町村のペアを作成する
+                let mut put_item_list = Vec::<(String, HashMap<String, AttributeValue>)>::new();
+                for postal_code in completed_postal_codes {
+                    let records = pending_records.remove(&postal_code).unwrap();
+                    let owner = postal_code_to_owner.get(&postal_code).unwrap().clone();
+
+                    // 郵便番号に紐づく全ての住所情報を書き込む
+                    let addresses = records
+                        .into_iter()
+                        .map(record_to_address_attribute)
+                        .collect::<Vec<_>>();
+                    let item = HashMap::from([
+                        ("postal_code".to_string(), AttributeValue::S(postal_code)),
+                        ("addresses".to_string(), AttributeValue::L(addresses)),
+                    ]);
+
+                    put_item_list.push((owner, item));
+                }
+
+                let deleted_postal_codes = national_local_government_code_to_deleted
+                    .remove(&national_local_government_code)
+                    .unwrap_or_default();
+                window_item_count += put_item_list.len() + deleted_postal_codes.len();
+                window.push(MunicipalityWrite {
+                    national_local_government_code,
+                    is_changed,
+                    put_item_list,
+                    deleted_postal_codes,
+                });
+
+                //チェックポイントの間隔に達するまでは、書き込み内容を溜めておく
+                if window_item_count < CHECKPOINT_ITEM_COUNT {
+                    continue;
                 }
             }
-        }
+            if window.is_empty() {
+                if is_last_group {
+                    break;
+                }
+                continue;
+            }
+
+            let window_started_at = Instant::now();
+            let municipality_count = window.len();
+            let item_count = window_item_count;
+            window_item_count = 0;
+
+            // 書き込む項目と削除する項目の現在の内容をまとめて取得する
+            let postal_codes = window
+                .iter()
+                .flat_map(|write| {
+                    write
+                        .put_item_list
+                        .iter()
+                        .map(|(_, item)| item.get("postal_code").unwrap().as_s().unwrap().clone())
+                        .chain(write.deleted_postal_codes.iter().cloned())
+                })
+                .collect::<Vec<_>>();
+            let current_items = crate::batch_get::batch_get_postal_code_items(
+                client,
+                &table_name,
+                &postal_codes,
+                batch_write_config,
+            )
+            .await?;
+
+            let failed_count_before = writer.failed_count;
+            let history_failed_count_before = history_writer.failed_count;
+            //今回書き込む項目を書き込む市区町村 (書き込めなかった場合にハッシュ値を書き込まないようにする)
+            let mut written_owners = HashSet::<String>::new();
+
+            for MunicipalityWrite {
+                national_local_government_code,
+                is_changed,
+                mut put_item_list,
+                deleted_postal_codes,
+            } in window.drain(..)
+            {
+                // 住所が最後に変更されたデータセットのバージョンを設定し、住所が変更される項目は変更前の住所を履歴に残す
+                let mut history_list = Vec::<WriteRequest>::new();
+                for (_, item) in &mut put_item_list {
+                    let postal_code = item.get("postal_code").unwrap().as_s().unwrap().clone();
+                    if let Some(history) = crate::history::apply_dataset_version(
+                        item,
+                        current_items.get(&postal_code),
+                        &update_version,
+                    )? {
+                        history_list.push(history);
+                    }
+                }
+
+                // ドライランであれば、現在の項目と比較した差分をレポートに記録する
+                if request.dry_run {
+                    for (owner, item) in &put_item_list {
+                        let postal_code = item.get("postal_code").unwrap().as_s().unwrap();
+                        report.add_put(owner, postal_code, current_items.get(postal_code), item);
+                    }
+                    continue;
+                }
+
+                for (owner, item) in put_item_list {
+                    //現在の項目と内容が変わらなければ書き込まない
+                    let postal_code = item.get("postal_code").unwrap().as_s().unwrap();
+                    if current_items.get(postal_code) != Some(&item) {
+                        let put_request = PutRequest::builder().set_item(Some(item)).build();
+
+                        let req = WriteRequest::builder().put_request(put_request).build();
+
+                        writer.push(req).await?;
+                    }
+
+                    *owner_to_remaining.get_mut(&owner).unwrap() -= 1;
+                    written_owners.insert(owner);
+                }
+
+                // 消滅した郵便番号を削除する
+                for postal_code in deleted_postal_codes {
+                    tracing::info!(postal_code = postal_code, "delete postal code");
+
+                    if let Some(current) = current_items.get(&postal_code) {
+                        history_list
+                            .push(crate::history::history_request(current, &update_version)?);
+                    }
+
+                    let delete_request = DeleteRequest::builder()
+                        .key("postal_code", AttributeValue::S(postal_code))
+                        .build();
+                    let req = WriteRequest::builder()
+                        .delete_request(delete_request)
+                        .build();
+
+                    writer.push(req).await?;
+                }
+
+                if is_changed {
+                    written_owners.insert(national_local_government_code.clone());
+                    waiting_owners.insert(national_local_government_code);
+                }
+
+                for req in history_list {
+                    history_writer.push(req).await?;
+                }
+            }
+            if request.dry_run {
+                if is_last_group {
+                    break;
+                }
+                continue;
+            }
+
+            // ハッシュ値は市区町村の住所情報の書き込みがすべて完了してから書き込む
+            writer.flush().await?;
+            history_writer.flush().await?;
+
+            // 書き込めなかった項目がある場合は、次回の更新で再度書き込まれるようにハッシュ値を更新しない
+            if writer.failed_count > failed_count_before
+                || history_writer.failed_count > history_failed_count_before
+            {
+                for owner in written_owners {
+                    tracing::error!(national_local_government_code = owner, "skip writing hash");
+                    waiting_owners.remove(&owner);
+                    failed_owners.insert(owner);
+                }
+            }
+
+            // 書き込む項目がすべて書き込まれた市区町村のハッシュ値を書き込む
+            let completed_owners = waiting_owners
+                .iter()
+                .filter(|owner| owner_to_remaining.get(*owner).copied().unwrap_or(0) == 0)
+                .cloned()
+                .collect::<Vec<_>>();
+            for owner in completed_owners {
+                waiting_owners.remove(&owner);
+
+                let hash = national_local_government_code_to_hash
+                    .get(&owner)
+                    .unwrap()
+                    .clone();
+                let postal_codes = national_local_government_code_to_postal_codes
+                    .remove(&owner)
+                    .unwrap();
+                let put_request = PutRequest::builder()
+                    .item("id", AttributeValue::S(owner))
+                    .item("hash", AttributeValue::S(hash))
+                    .item("postal_codes", AttributeValue::Ss(postal_codes))
+                    .build();
+                hash_writer
+                    .push(WriteRequest::builder().put_request(put_request).build())
+                    .await?;
+            }
 
-        if requests.len() > 0 {
-            count += requests.len();
+            tracing::info!(
+                municipality_count = municipality_count,
+                item_count = item_count,
+                elapsed_millis = window_started_at.elapsed().as_millis() as u64,
+                "checkpoint written"
+            );
+            if is_last_group {
+                break;
+            }
+        }
 
-            send_batch_write_item(client, table_name.to_owned(), requests.clone()).await?;
+        // ドライランであれば、差分のレポートを返して終了する
+        if request.dry_run {
+            let response_data = ResponseData {
+                dry_run_report: Some(report),
+                validation_report: Some(validation_report),
+                ..ResponseData::new(0, "dry run".to_string())
+            };
+            return Ok(serde_json::json!(response_data));
+        }
 
-            requests.clear();
+        // 存在しなくなったnational_local_government_codeの郵便番号を削除する
+        // 件数が少ないため、すべての市区町村をまとめて一つのチェックポイントとして書き込む
+        let remaining_millis = event.context.deadline as i64 - now_millis() as i64;
+        if continuation.is_none() && remaining_millis < deadline_margin_millis as i64 {
+            if let Some(national_local_government_code) =
+                vanished_national_local_government_codes.first()
+            {
+                tracing::info!(
+                    national_local_government_code = national_local_government_code,
+                    "suspend update before timeout"
+                );
+                continuation = Some(national_local_government_code.clone());
+            }
         }
+        if continuation.is_none() && !vanished_national_local_government_codes.is_empty() {
+            let failed_count_before = writer.failed_count;
+            let history_failed_count_before = history_writer.failed_count;
 
-        // 最後にハッシュ値をDynamoDBに書き込む
-        let put_request = PutRequest::builder()
-            .item("id", AttributeValue::S(HASH_ITEM_KEY.to_string()))
-            .item("hash", AttributeValue::S(ken_all_data.all_contents_hash))
-            .build();
-        let req = WriteRequest::builder().put_request(put_request).build();
-        requests.push(req);
-
-        for (id, hash) in ken_all_data.national_local_government_code_to_hash {
-            let put_request = PutRequest::builder()
-                .item("id", AttributeValue::S(id))
-                .item("hash", AttributeValue::S(hash))
-                .build();
-            let req = WriteRequest::builder().put_request(put_request).build();
-            requests.push(req);
+            let deleted_postal_codes = vanished_national_local_government_codes
+                .iter()
+                .flat_map(|code| {
+                    national_local_government_code_to_deleted
+                        .remove(code)
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>();
+            let current_items = crate::batch_get::batch_get_postal_code_items(
+                client,
+                &table_name,
+                &deleted_postal_codes,
+                batch_write_config,
+            )
+            .await?;
+            for postal_code in deleted_postal_codes {
+                tracing::info!(postal_code = postal_code, "delete postal code");
 
-            // 大きなデータを送信するとエラーになるため適当な数ごとにバッチリクエストを実行する
-            if requests.len() == 25 {
-                send_batch_write_item(client, hash_table_name.to_owned(), requests.clone()).await?;
+                // 削除する項目の住所を履歴に残す
+                if let Some(current) = current_items.get(&postal_code) {
+                    history_writer
+                        .push(crate::history::history_request(current, &update_version)?)
+                        .await?;
+                }
+
+                let delete_request = DeleteRequest::builder()
+                    .key("postal_code", AttributeValue::S(postal_code))
+                    .build();
+                let req = WriteRequest::builder()
+                    .delete_request(delete_request)
+                    .build();
 
-                requests.clear();
+                writer.push(req).await?;
+            }
+
+            writer.flush().await?;
+            history_writer.flush().await?;
+            let write_failed = writer.failed_count > failed_count_before
+                || history_writer.failed_count > history_failed_count_before;
+
+            for national_local_government_code in vanished_national_local_government_codes {
+                // 前回この市区町村に含まれていた郵便番号の項目が書き直されていなければ、ハッシュ値を削除しない
+                if write_failed
+                    || failed_owners.contains(&national_local_government_code)
+                    || owner_to_remaining
+                        .get(&national_local_government_code)
+                        .is_some_and(|remaining| *remaining > 0)
+                {
+                    tracing::error!(
+                        national_local_government_code = national_local_government_code,
+                        "skip writing hash"
+                    );
+                    continue;
+                }
+
+                let delete_request = DeleteRequest::builder()
+                    .key("id", AttributeValue::S(national_local_government_code))
+                    .build();
+                hash_writer
+                    .push(
+                        WriteRequest::builder()
+                            .delete_request(delete_request)
+                            .build(),
+                    )
+                    .await?;
             }
         }
 
-        if requests.len() > 0 {
-            send_batch_write_item(client, hash_table_name.to_owned(), requests).await?;
+        writer.flush().await?;
+        history_writer.flush().await?;
+        hash_writer.flush().await?;
+        tracing::info!(
+            elapsed_millis = started_at.elapsed().as_millis() as u64,
+            "end write"
+        );
+        count = writer.count;
+        // ハッシュ値を書き込めなかった市区町村は次回の更新で再度書き込まれる
+        failed_count = writer.failed_count + history_writer.failed_count + hash_writer.failed_count;
+
+        // すべての市区町村の更新が完了した場合のみ、最後にコンテンツ全体のハッシュ値を書き込む
+        if continuation.is_none() && failed_count == 0 {
+            client
+                .put_item()
+                .table_name(hash_table_name.clone())
+                .item("id", AttributeValue::S(HASH_ITEM_KEY.to_string()))
+                .item("hash", AttributeValue::S(all_contents_hash))
+                .item(
+                    "national_local_government_codes",
+                    AttributeValue::Ss(national_local_government_code_list),
+                )
+                .send()
+                .await?;
         }
     }
 
+    // すべての更新が完了した場合のみ、次回の条件付きリクエストのために検証子を保存する
+    if !request.dry_run && continuation.is_none() && failed_count == 0 {
+        put_source_validators(client, &hash_table_name, validators).await?;
+    }
+
     let response_data = ResponseData {
-        code: 0,
+        code: if failed_count > 0 { 1 } else { 0 },
         count: count,
-        message: "".to_string(),
+        failed_count: failed_count,
+        message: if failed_count > 0 {
+            "some items could not be written".to_string()
+        } else if continuation.is_some() {
+            "suspended before timeout".to_string()
+        } else {
+            "".to_string()
+        },
+        //続きの処理でも同じデータを読み込むように、読み込み元の指定は引き継ぐ
+        continuation: continuation.map(|_| RequestData {
+            dataset_version: Some(update_version.dataset_version),
+            ken_all_source: request.ken_all_source.clone(),
+            jigyosyo_source: request.jigyosyo_source.clone(),
+            ken_all_rome_source: request.ken_all_rome_source.clone(),
+            ..Default::default()
+        }),
+        //変更がなかった場合のドライランは空のレポートを返す
+        error: None,
+        dry_run_report: if request.dry_run { Some(report) } else { None },
+        validation_report: Some(validation_report),
     };
 
     Ok(serde_json::json!(response_data))
 }
 
-// batch_write_itemのリクエストを送信する
-async fn send_batch_write_item(
+// ハッシュテーブルに保存されている、前回ダウンロードしたファイルの検証子を取得する
+async fn get_source_validators(
     client: &Client,
-    table_name: String,
-    requests: Vec<WriteRequest>,
+    hash_table_name: &str,
+) -> Result<HashMap<String, CacheValidator>, Error> {
+    let mut validators = HashMap::<String, CacheValidator>::new();
+    for name in ["ken_all", "jigyosyo", "ken_all_rome"] {
+        let item = client
+            .get_item()
+            .table_name(hash_table_name)
+            .key(
+                "id",
+                AttributeValue::S(format!("{}{}", SOURCE_ITEM_KEY_PREFIX, name)),
+            )
+            .send()
+            .await?;
+
+        if let Some(item) = item.item() {
+            let get = |key: &str| item.get(key).and_then(|value| value.as_s().ok()).cloned();
+            validators.insert(
+                name.to_string(),
+                CacheValidator {
+                    url: get("url").unwrap_or_default(),
+                    etag: get("etag"),
+                    last_modified: get("last_modified"),
+                },
+            );
+        }
+    }
+
+    Ok(validators)
+}
+
+// ダウンロードしたファイルの検証子をハッシュテーブルに保存する
+async fn put_source_validators(
+    client: &Client,
+    hash_table_name: &str,
+    validators: Vec<(String, CacheValidator)>,
 ) -> Result<(), Error> {
+    for (name, validator) in validators {
+        let mut request = client
+            .put_item()
+            .table_name(hash_table_name)
+            .item(
+                "id",
+                AttributeValue::S(format!("{}{}", SOURCE_ITEM_KEY_PREFIX, name)),
+            )
+            .item("url", AttributeValue::S(validator.url));
+        if let Some(etag) = validator.etag {
+            request = request.item("etag", AttributeValue::S(etag));
+        }
+        if let Some(last_modified) = validator.last_modified {
+            request = request.item("last_modified", AttributeValue::S(last_modified));
+        }
+        request.send().await?;
+    }
+
+    Ok(())
+}
+
+// 更新に使用するDynamoDBのテーブル名
+struct TableNames {
+    postal_code: String,
+    hash: String,
+    history: String,
+}
+
+// 月次の差分ファイルを取得して、追加データと削除データをDynamoDBに反映する
+async fn diff_update(
+    client: &Client,
+    tables: &TableNames,
+    source_format: SourceFormat,
+    diff_month: &str,
+    rome_source: &Source,
+    batch_write_config: BatchWriteConfig,
+    version: &crate::history::UpdateVersion,
+) -> Result<ResponseData, Error> {
+    let table_name = tables.postal_code.as_str();
+    let hash_table_name = tables.hash.as_str();
+
+    // 年月がYYMM形式でなければ処理をしない
+    if diff_month.len() != 4 || !diff_month.chars().all(|c| c.is_ascii_digit()) {
+        return Ok(ResponseData::new(
+            1,
+            format!("diff_month must be YYMM: {}", diff_month),
+        ));
+    }
+
+    // 同じ差分ファイルを二重に適用しないように、適用済みかを確認する
+    let diff_item_key = format!("{}{}", DIFF_ITEM_KEY_PREFIX, diff_month);
+    let diff_item = client
+        .get_item()
+        .table_name(hash_table_name)
+        .key("id", AttributeValue::S(diff_item_key.clone()))
+        .send()
+        .await?;
+    if diff_item.item().is_some() {
+        return Ok(ResponseData::new(
+            0,
+            format!("diff {} is already applied", diff_month),
+        ));
+    }
+
+    tracing::info!(diff_month = diff_month, "start ken_all diff");
+    let diff_data =
+        match crate::ken_all::ken_all_diff_records(source_format, diff_month, rome_source) {
+            Ok(diff_data) => diff_data,
+            Err(err) => return Ok(ResponseData::ingest_error(err)),
+        };
+    tracing::info!("end ken_all diff");
+
+    //追加する住所レコードを検証し、違反の件数が許容件数を超えていれば適用を中止する
+    let validation_thresholds = match ValidationThresholds::from_env() {
+        Ok(validation_thresholds) => validation_thresholds,
+        Err(err) => return Ok(ResponseData::ingest_error(err)),
+    };
+    let mut validator = Validator::new(validation_thresholds);
+    for record in &diff_data.added_postal_code_record_list {
+        validator.check(record);
+    }
+    for err in &diff_data.invalid_rows {
+        validator.check_row_error(err);
+    }
+    let validation_report = validator.finish();
+    if validation_report.failed {
+        let err = IngestError::Validation {
+            rules: validation_report.failed_rules(),
+        };
+        return Ok(ResponseData {
+            validation_report: Some(validation_report),
+            ..ResponseData::ingest_error(err)
+        });
+    }
+
+    // 郵便番号ごとに追加する住所と削除する住所をまとめる
+    let mut postal_code_list = Vec::<String>::new();
+    let mut postal_code_to_changes =
+        HashMap::<String, (Vec<AttributeValue>, Vec<AttributeValue>)>::new();
+    for (records, is_added) in [
+        (diff_data.deleted_postal_code_record_list, false),
+        (diff_data.added_postal_code_record_list, true),
+    ] {
+        for record in records {
+            let changes = postal_code_to_changes
+                .entry(record.postal_code.clone())
+                .or_insert_with(|| {
+                    postal_code_list.push(record.postal_code.clone());
+                    (Vec::new(), Vec::new())
+                });
+
+            if is_added {
+                changes.0.push(record_to_address_attribute(record));
+            } else {
+                changes.1.push(record_to_address_attribute(record));
+            }
+        }
+    }
+
+    let mut writer = BatchWriter::new(client, table_name.to_owned(), batch_write_config);
+    let mut history_writer = BatchWriter::new(client, tables.history.clone(), batch_write_config);
+    for postal_code in postal_code_list {
+        let (added, deleted) = postal_code_to_changes.remove(&postal_code).unwrap();
+
+        // 現在DynamoDBに保存されている住所を取得
+        let item = client
+            .get_item()
+            .table_name(table_name)
+            .key("postal_code", AttributeValue::S(postal_code.clone()))
+            .send()
+            .await?;
+        let mut addresses = match item.item() {
+            Some(item) => item_to_address_list(item)?,
+            None => Vec::new(),
+        };
+
+        // 削除データの住所を取り除いてから、追加データの住所を加える
+        // 削除データと追加データでは更新の表示・変更理由が異なるため、それらを除いて比較する
+        addresses.retain(|address| {
+            !deleted
+                .iter()
+                .any(|deleted| crate::history::is_same_address_attribute(address, deleted))
+        });
+        for address in added {
+            match addresses
+                .iter()
+                .position(|current| crate::history::is_same_address_attribute(current, &address))
+            {
+                Some(index) => addresses[index] = address,
+                None => addresses.push(address),
+            }
+        }
+
+        let req = if addresses.is_empty() {
+            // 住所が一つも残らなければ郵便番号ごと削除し、削除前の住所を履歴に残す
+            if let Some(current) = item.item() {
+                history_writer
+                    .push(crate::history::history_request(current, version)?)
+                    .await?;
+            }
+
+            let delete_request = DeleteRequest::builder()
+                .key("postal_code", AttributeValue::S(postal_code))
+                .build();
+            WriteRequest::builder()
+                .delete_request(delete_request)
+                .build()
+        } else {
+            let mut new_item = HashMap::from([
+                ("postal_code".to_string(), AttributeValue::S(postal_code)),
+                ("addresses".to_string(), AttributeValue::L(addresses)),
+            ]);
+            // 住所が変更される場合は、変更前の住所を履歴に残す
+            if let Some(history) =
+                crate::history::apply_dataset_version(&mut new_item, item.item(), version)?
+            {
+                history_writer.push(history).await?;
+            }
+
+            let put_request = PutRequest::builder().set_item(Some(new_item)).build();
+            WriteRequest::builder().put_request(put_request).build()
+        };
+        writer.push(req).await?;
+    }
+    writer.flush().await?;
+    history_writer.flush().await?;
+
+    // 書き込めなかった項目がある場合は、再度適用できるように適用済みとして記録しない
+    let failed_count = writer.failed_count + history_writer.failed_count;
+    if failed_count > 0 {
+        return Ok(ResponseData {
+            count: writer.count,
+            failed_count: failed_count,
+            validation_report: Some(validation_report),
+            ..ResponseData::new(
+                1,
+                format!("some items of diff {} could not be written", diff_month),
+            )
+        });
+    }
+
+    // 適用済みの差分ファイルとして記録する
     client
-        .batch_write_item()
-        .request_items(table_name, requests)
+        .put_item()
+        .table_name(hash_table_name)
+        .item("id", AttributeValue::S(diff_item_key))
         .send()
         .await?;
 
-    Ok(())
+    return Ok(ResponseData {
+        count: writer.count,
+        validation_report: Some(validation_report),
+        ..ResponseData::new(0, "".to_string())
+    });
+}
+
+// DynamoDBに保存されている項目から住所情報(Map)のリストを取得する
+fn item_to_address_list(
+    item: &HashMap<String, AttributeValue>,
+) -> Result<Vec<AttributeValue>, IngestError> {
+    if let Some(addresses) = item.get("addresses") {
+        return match addresses.as_l() {
+            Ok(addresses) => Ok(addresses.clone()),
+            Err(_) => Err(IngestError::InvalidItem {
+                key: item
+                    .get("postal_code")
+                    .and_then(|postal_code| postal_code.as_s().ok())
+                    .cloned()
+                    .unwrap_or_default(),
+                message: "addresses is not a list".to_string(),
+            }),
+        };
+    }
+
+    //住所のリストを持たない古い形式の項目は、項目自体を一つの住所情報として扱う
+    let mut address = item.clone();
+    address.remove("postal_code");
+    address.insert(
+        "record_type".to_string(),
+        AttributeValue::S(RecordType::Town.as_str().to_string()),
+    );
+    return Ok(vec![AttributeValue::M(address)]);
+}
+
+// 二つのレコードが同じ住所を表しているか判定する
+fn is_same_address(a: &PostalCodeRecord, b: &PostalCodeRecord) -> bool {
+    a.record_type == b.record_type
+        && a.business.as_ref().map(|business| &business.name)
+            == b.business.as_ref().map(|business| &business.name)
+        && a.prefecture == b.prefecture
+        && a.city == b.city
+        && a.town == b.town
+        && a.prefecture_kana == b.prefecture_kana
+        && a.city_kana == b.city_kana
+        && a.town_kana == b.town_kana
+        && a.annotation == b.annotation
+        && a.chiwari == b.chiwari
+        && a.town_roma == b.town_roma
+}
+
+// 住所レコードをDynamoDBに保存する住所情報(Map)に変換する
+fn record_to_address_attribute(record: PostalCodeRecord) -> AttributeValue {
+    let mut address = HashMap::from([
+        (
+            "record_type".to_string(),
+            AttributeValue::S(record.record_type.as_str().to_string()),
+        ),
+        (
+            "prefecture".to_string(),
+            AttributeValue::S(record.prefecture),
+        ),
+        (
+            "prefecture_kana".to_string(),
+            AttributeValue::S(record.prefecture_kana),
+        ),
+        ("city".to_string(), AttributeValue::S(record.city)),
+        ("city_kana".to_string(), AttributeValue::S(record.city_kana)),
+        ("town".to_string(), AttributeValue::S(record.town)),
+        ("town_kana".to_string(), AttributeValue::S(record.town_kana)),
+        (
+            "city_name".to_string(),
+            city_name_to_attribute(record.city_name),
+        ),
+        (
+            "changed".to_string(),
+            AttributeValue::S(record.is_change.as_str().to_string()),
+        ),
+    ]);
+
+    //変更理由はken_allの住所のみが持つ
+    if let Some(change_reason) = record.change_reason {
+        address.insert(
+            "change_reason".to_string(),
+            AttributeValue::N(change_reason.code().to_string()),
+        );
+    }
+
+    //大口事業所個別番号であれば、事業所の情報も保存する
+    if let Some(business) = record.business {
+        address.insert(
+            "business_name".to_string(),
+            AttributeValue::S(business.name),
+        );
+        address.insert(
+            "business_name_kana".to_string(),
+            AttributeValue::S(business.name_kana),
+        );
+        address.insert(
+            "business_address".to_string(),
+            AttributeValue::S(business.address),
+        );
+        address.insert(
+            "handling_office".to_string(),
+            AttributeValue::S(business.handling_office),
+        );
+        address.insert(
+            "is_post_office_box".to_string(),
+            AttributeValue::Bool(business.is_post_office_box),
+        );
+    }
+
+    //ローマ字表記が結合されていれば保存する
+    if !record.prefecture_roma.is_empty() {
+        address.insert(
+            "prefecture_roma".to_string(),
+            AttributeValue::S(record.prefecture_roma),
+        );
+        address.insert("city_roma".to_string(), AttributeValue::S(record.city_roma));
+        address.insert("town_roma".to_string(), AttributeValue::S(record.town_roma));
+    }
+
+    //町域名の括弧内の注記があれば、構造化して保存する
+    if let Some(annotation) = record.annotation {
+        let to_list = |values: Vec<String>| {
+            AttributeValue::L(values.into_iter().map(AttributeValue::S).collect())
+        };
+        let mut annotation_map = HashMap::from([
+            ("text".to_string(), AttributeValue::S(annotation.text)),
+            ("chome".to_string(), to_list(annotation.chome)),
+            ("banchi".to_string(), to_list(annotation.banchi)),
+            (
+                "excluded".to_string(),
+                AttributeValue::Bool(annotation.excluded),
+            ),
+            ("others".to_string(), to_list(annotation.others)),
+        ]);
+        if let Some(floor) = annotation.floor {
+            annotation_map.insert("floor".to_string(), AttributeValue::S(floor));
+        }
+        address.insert("annotation".to_string(), AttributeValue::M(annotation_map));
+    }
+
+    //地割で区分された町域であれば、地割番号の一覧を保存する
+    if !record.chiwari.is_empty() {
+        address.insert(
+            "chiwari".to_string(),
+            AttributeValue::L(
+                record
+                    .chiwari
+                    .iter()
+                    .map(|chiwari| AttributeValue::N(chiwari.to_string()))
+                    .collect(),
+            ),
+        );
+    }
+
+    AttributeValue::M(address)
+}
+
+// 郡・市区町村・区に分けた市区町村名をDynamoDBに保存する形式(Map)に変換する
+// 郡と区は該当する場合のみ保存する
+fn city_name_to_attribute(city_name: CityName) -> AttributeValue {
+    let mut attribute = HashMap::from([("city".to_string(), AttributeValue::S(city_name.city))]);
+    if let Some(county) = city_name.county {
+        attribute.insert("county".to_string(), AttributeValue::S(county));
+    }
+    if let Some(ward) = city_name.ward {
+        attribute.insert("ward".to_string(), AttributeValue::S(ward));
+    }
+
+    AttributeValue::M(attribute)
+}
+
+// 現在時刻をUNIXエポックからのミリ秒で取得する
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 async fn is_hash_change(
     client: &Client,
     table_name: String,
-    cache: &mut HashMap<String, String>,
+    cache: &mut HashMap<String, HashItem>,
     id: String,
     hash: &String,
 ) -> Result<bool, Error> {
-    let cache_item = cache.get(&id);
+    let hash_item = get_hash_item(client, table_name, cache, id).await?;
 
-    //すでにDynamoDBから取得していたら、取得済みの値と比較
-    let changed = if let Some(cached_hash) = cache_item {
-        cached_hash != hash
-    } else {
-        //まだハッシュ値を持っていなければ、DynamoDBから取得
-        let hash_item = client
-            .get_item()
-            .table_name(table_name)
-            .key("id", AttributeValue::S(id.clone()))
-            .send()
-            .await?;
+    // 計算した結果と取得したハッシュを比較して異なっていたら変更されていると判定
+    // DynamoDBに項目がない場合は空文字になっているため、変更があったと判定される
+    return Ok(&hash_item.hash != hash);
+}
+
+async fn get_hash_item(
+    client: &Client,
+    table_name: String,
+    cache: &mut HashMap<String, HashItem>,
+    id: String,
+) -> Result<HashItem, Error> {
+    //すでにDynamoDBから取得していたら、取得済みの値を使用
+    if let Some(cached_item) = cache.get(&id) {
+        return Ok(cached_item.clone());
+    }
+
+    //まだハッシュ値を持っていなければ、DynamoDBから取得
+    let hash_item = client
+        .get_item()
+        .table_name(table_name)
+        .key("id", AttributeValue::S(id.clone()))
+        .send()
+        .await?;
 
-        if let Some(item) = hash_item.item() {
-            let contents_hash = item.get("hash").unwrap().as_s().unwrap();
-            //取得したハッシュ値をキャッシュに保存
-            cache.insert(id, contents_hash.to_owned());
+    let hash_item = if let Some(item) = hash_item.item() {
+        let invalid_item = |message: &str| IngestError::InvalidItem {
+            key: id.clone(),
+            message: message.to_string(),
+        };
+        let string_set = |name: &str| match item.get(name) {
+            Some(value) => value
+                .as_ss()
+                .cloned()
+                .map_err(|_| invalid_item(&format!("{} is not a string set", name))),
+            None => Ok(Vec::new()),
+        };
 
-            // 計算した結果と取得したハッシュを比較して異なっていたら変更されていると判定
-            contents_hash != hash
-        } else {
-            //DynamoDB上に項目がなかったため、空文字をキャッシュに保存
-            cache.insert(id, "".to_string());
-            //DynamoDBに項目がない場合は変更があったと判定
-            true
+        HashItem {
+            hash: item
+                .get("hash")
+                .and_then(|hash| hash.as_s().ok())
+                .ok_or_else(|| invalid_item("hash is not a string"))?
+                .to_owned(),
+            postal_codes: string_set("postal_codes")?,
+            national_local_government_codes: string_set("national_local_government_codes")?,
         }
+    } else {
+        //DynamoDB上に項目がなかったため、空の項目をキャッシュに保存
+        HashItem::default()
     };
 
-    return Ok(changed);
+    //取得した項目をキャッシュに保存
+    cache.insert(id, hash_item.clone());
+
+    return Ok(hash_item);
 }
 
 #[tokio::main]
@@ -3,15 +3,131 @@ use std::hash::{Hash, Hasher};
 use digest::Update;
 use sha2::Sha256;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Changed {
     NoChange,
     Changed,
     Deleted,
 }
 
-#[derive(Debug)]
+impl Changed {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Changed::NoChange => "no_change",
+            Changed::Changed => "changed",
+            Changed::Deleted => "deleted",
+        }
+    }
+}
+
+/// ken_allの変更理由
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeReason {
+    /// 変更なし
+    NoChange = 0,
+    /// 市政・区政・町政・分区・政令指定都市施行
+    MunicipalSystem = 1,
+    /// 住居表示の実施
+    ResidenceIndication = 2,
+    /// 区画整理
+    LandReadjustment = 3,
+    /// 郵便区調整等
+    PostalDistrictAdjustment = 4,
+    /// 訂正
+    Correction = 5,
+    /// 廃止（廃止データのみ使用）
+    Abolition = 6,
+}
+
+impl ChangeReason {
+    /// ken_allの変更理由の値から変換する
+    pub fn from_code(code: &str) -> Option<ChangeReason> {
+        match code {
+            "0" => Some(ChangeReason::NoChange),
+            "1" => Some(ChangeReason::MunicipalSystem),
+            "2" => Some(ChangeReason::ResidenceIndication),
+            "3" => Some(ChangeReason::LandReadjustment),
+            "4" => Some(ChangeReason::PostalDistrictAdjustment),
+            "5" => Some(ChangeReason::Correction),
+            "6" => Some(ChangeReason::Abolition),
+            _ => None,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        return *self as u8;
+    }
+}
+
+/// hasher_addで計算するハッシュの形式のバージョン
+/// hasher_addの内容を変更した場合は必ず値を更新すること (保存済みのハッシュ値と一致しなくなり、全件が書き直される)
+pub const HASH_FORMAT_VERSION: u32 = 7;
+
+/// レコードの種別
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecordType {
+    /// 住所の郵便番号(ken_all)
+    Town,
+    /// 大口事業所個別番号(jigyosyo)
+    Business,
+}
+
+impl RecordType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordType::Town => "town",
+            RecordType::Business => "business",
+        }
+    }
+}
+
+/// 大口事業所個別番号の事業所情報
+#[derive(Debug, Clone, PartialEq)]
+pub struct BusinessInfo {
+    /// 大口事業所名
+    pub name: String,
+    /// 大口事業所名カナ 全角カタカナ
+    pub name_kana: String,
+    /// 小字名、丁目、番地等
+    pub address: String,
+    /// 取扱局
+    pub handling_office: String,
+    /// 個別番号の種別が私書箱であればtrue、大口事業所であればfalse
+    pub is_post_office_box: bool,
+}
+
+/// 町域名の括弧内に書かれている注記を構造化したもの
+/// 例: 「大通西（１～１９丁目）」「藻岩下（１９５、１９６番地）」「センチュリー（地階・階層不明）」
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TownAnnotation {
+    /// 括弧内の元の文字列 (複数行に分割されている場合は連結したもの)
+    pub text: String,
+    /// 高層ビルの階層 「地階・階層不明」「１階」など
+    pub floor: Option<String>,
+    /// 丁目の一覧 「１～１９丁目」など
+    pub chome: Vec<String>,
+    /// 番地の一覧・範囲 「１９５」「１９６番地」など
+    pub banchi: Vec<String>,
+    /// 「を除く」「以外」で終わる、除外する範囲を表す注記であればtrue
+    pub excluded: bool,
+    /// 上記に分類できなかった注記
+    pub others: Vec<String>,
+}
+
+/// 市区町村名を郡・市区町村・政令指定都市の区に分けたもの
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CityName {
+    /// 郡名 「余市郡」など (郡に属する町村のみ)
+    pub county: Option<String>,
+    /// 郡名・区名を除いた市区町村名 「横浜市」「仁木町」など
+    pub city: String,
+    /// 政令指定都市の区名 「中区」など (政令指定都市のみ)
+    pub ward: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct PostalCodeRecord {
+    pub record_type: RecordType,
     pub national_local_government_code: String,
     pub postal_code: String,
     pub prefecture_kana: String,
@@ -20,12 +136,24 @@ pub struct PostalCodeRecord {
     pub prefecture: String,
     pub city: String,
     pub town: String,
+    /// 市区町村名(city)を郡・市区町村・区に分けたもの
+    pub city_name: CityName,
+    /// 住所のローマ字表記 (KEN_ALL_ROMEから結合する。対応するデータがなければ空文字)
+    pub prefecture_roma: String,
+    pub city_roma: String,
+    pub town_roma: String,
     pub is_一つの町域が二つ以上の郵便番号で表示される: bool,
     pub is_小字毎に番地が起番されている町域: bool,
     pub is_丁目を有する町域: bool,
     pub is_一つの郵便番号で二以上の町域を表す: bool,
     pub is_change: Changed,
-    pub integrated: bool,
+    /// 変更理由 (ken_allのみ。大口事業所個別番号のデータには含まれていない)
+    pub change_reason: Option<ChangeReason>,
+    pub business: Option<BusinessInfo>,
+    pub annotation: Option<TownAnnotation>,
+    /// 岩手県の「第N地割」のように地割で区分された町域の地割番号の一覧
+    /// 町域名(town)には地割を除いた町名のみが入る
+    pub chiwari: Vec<u32>,
 }
 
 impl PostalCodeRecord {
@@ -44,7 +172,9 @@ impl PostalCodeRecord {
         is_一つの郵便番号で二以上の町域を表す: bool,
         is_change: Changed,
     ) -> PostalCodeRecord {
+        let city_name = crate::city_name::split_city_name(&national_local_government_code, &city);
         PostalCodeRecord {
+            record_type: RecordType::Town,
             national_local_government_code,
             postal_code,
             prefecture_kana,
@@ -53,24 +183,108 @@ impl PostalCodeRecord {
             prefecture,
             city,
             town,
+            city_name,
+            prefecture_roma: "".to_string(),
+            city_roma: "".to_string(),
+            town_roma: "".to_string(),
             is_一つの町域が二つ以上の郵便番号で表示される,
             is_小字毎に番地が起番されている町域,
             is_丁目を有する町域,
             is_一つの郵便番号で二以上の町域を表す,
             is_change,
-            integrated: false,
+            change_reason: None,
+            business: None,
+            annotation: None,
+            chiwari: Vec::new(),
+        }
+    }
+
+    /// 大口事業所個別番号のレコードを作成する
+    pub fn new_business(
+        national_local_government_code: String,
+        postal_code: String,
+        prefecture: String,
+        city: String,
+        town: String,
+        business: BusinessInfo,
+        is_change: Changed,
+    ) -> PostalCodeRecord {
+        let city_name = crate::city_name::split_city_name(&national_local_government_code, &city);
+        PostalCodeRecord {
+            record_type: RecordType::Business,
+            national_local_government_code,
+            postal_code,
+            //事業所データには住所のカナが含まれていない
+            prefecture_kana: "".to_string(),
+            city_kana: "".to_string(),
+            town_kana: "".to_string(),
+            prefecture,
+            city,
+            town,
+            city_name,
+            prefecture_roma: "".to_string(),
+            city_roma: "".to_string(),
+            town_roma: "".to_string(),
+            is_一つの町域が二つ以上の郵便番号で表示される: false,
+            is_小字毎に番地が起番されている町域: false,
+            is_丁目を有する町域: false,
+            is_一つの郵便番号で二以上の町域を表す: false,
+            is_change,
+            change_reason: None,
+            business: Some(business),
+            annotation: None,
+            chiwari: Vec::new(),
         }
     }
 
     pub fn hasher_add(&self, hasher: &mut Sha256) {
-        hasher.update(self.national_local_government_code.as_bytes());
-        hasher.update(self.postal_code.as_bytes());
-        hasher.update(self.prefecture_kana.as_bytes());
-        hasher.update(self.city_kana.as_bytes());
-        hasher.update(self.town_kana.as_bytes());
-        hasher.update(self.prefecture.as_bytes());
-        hasher.update(self.city.as_bytes());
-        hasher.update(self.town.as_bytes());
+        //フィールドの境界が曖昧にならないように、各フィールドの後に区切り文字を入れる
+        let mut update = |value: &[u8]| {
+            hasher.update(value);
+            hasher.update(b"\0");
+        };
+
+        update(self.national_local_government_code.as_bytes());
+        update(self.postal_code.as_bytes());
+        update(self.prefecture_kana.as_bytes());
+        update(self.city_kana.as_bytes());
+        update(self.town_kana.as_bytes());
+        update(self.prefecture.as_bytes());
+        update(self.city.as_bytes());
+        update(self.town.as_bytes());
+        update(self.city_name.county.as_deref().unwrap_or("").as_bytes());
+        update(self.city_name.city.as_bytes());
+        update(self.city_name.ward.as_deref().unwrap_or("").as_bytes());
+        update(self.prefecture_roma.as_bytes());
+        update(self.city_roma.as_bytes());
+        update(self.town_roma.as_bytes());
+        update(self.record_type.as_str().as_bytes());
+        update(self.is_change.as_str().as_bytes());
+        if let Some(change_reason) = self.change_reason {
+            update(change_reason.code().to_string().as_bytes());
+        }
+        if let Some(business) = &self.business {
+            update(business.name.as_bytes());
+            update(business.name_kana.as_bytes());
+            update(business.address.as_bytes());
+            update(business.handling_office.as_bytes());
+            update(if business.is_post_office_box {
+                b"1"
+            } else {
+                b"0"
+            });
+        }
+        if let Some(annotation) = &self.annotation {
+            update(annotation.text.as_bytes());
+            update(annotation.floor.as_deref().unwrap_or("").as_bytes());
+            update(annotation.chome.join("、").as_bytes());
+            update(annotation.banchi.join("、").as_bytes());
+            update(if annotation.excluded { b"1" } else { b"0" });
+            update(annotation.others.join("、").as_bytes());
+        }
+        for chiwari in &self.chiwari {
+            update(chiwari.to_string().as_bytes());
+        }
     }
 
     #[allow(dead_code)]
@@ -0,0 +1,584 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use encoding_rs::{Decoder, DecoderResult, SHIFT_JIS, UTF_8};
+use flate2::read::DeflateDecoder;
+use reqwest::blocking::Response;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::StatusCode;
+use zip::result::ZipError;
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::error::IngestError;
+
+/// 郵便番号データの読み込み元
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// URLからzipファイルをダウンロードする
+    Url(String),
+    /// ローカルのzipファイル
+    LocalZip(PathBuf),
+    /// ローカルの展開済みのCSVファイル
+    LocalCsv(PathBuf),
+    /// 標準入力から展開済みのCSVを読み込む
+    Stdin,
+}
+
+impl Source {
+    /// イベントや環境変数で指定された文字列から読み込み元を取得する
+    /// 「http://」「https://」で始まればURL、「-」か「stdin」であれば標準入力、
+    /// それ以外はローカルのファイルとみなし、拡張子が「.zip」であればzipファイル、そうでなければCSVファイルとして扱う
+    pub fn from_spec(spec: &str) -> Source {
+        if spec.starts_with("http://") || spec.starts_with("https://") {
+            return Source::Url(spec.to_string());
+        }
+        if spec == "-" || spec == "stdin" {
+            return Source::Stdin;
+        }
+
+        let path = PathBuf::from(spec.strip_prefix("file://").unwrap_or(spec));
+        let is_zip = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"));
+        if is_zip {
+            Source::LocalZip(path)
+        } else {
+            Source::LocalCsv(path)
+        }
+    }
+
+    /// イベントで指定された読み込み元、環境変数で指定された読み込み元、日本郵便のURLの順に優先して読み込み元を決定する
+    pub fn resolve(event_spec: Option<&str>, env_name: &str, default_url: &str) -> Source {
+        if let Some(spec) = event_spec {
+            return Source::from_spec(spec);
+        }
+        match std::env::var(env_name) {
+            Ok(spec) => Source::from_spec(&spec),
+            Err(_) => Source::Url(default_url.to_string()),
+        }
+    }
+
+    /// 読み込み元からデータを取得する
+    /// URLの場合は前回の検証子を使って条件付きでダウンロードし、更新されていなければNotModifiedを返す
+    pub fn fetch(&self, validator: Option<&CacheValidator>) -> Result<Fetched, IngestError> {
+        match self {
+            Source::Url(url) => {
+                //zipファイルをダウンロードする
+                let mut response = download_zip(url, validator)?;
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    return Ok(Fetched::NotModified);
+                }
+
+                //次回の条件付きリクエストのために、レスポンスヘッダーから検証子を取得しておく
+                let header = |name| {
+                    response
+                        .headers()
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .map(|value| value.to_string())
+                };
+                let validator = CacheValidator {
+                    url: url.clone(),
+                    etag: header(ETAG),
+                    last_modified: header(LAST_MODIFIED),
+                };
+
+                //zipファイルは展開せずに圧縮されたまま保持し、読み込む時に少しずつ展開する
+                let contents = response_to_bytes(url, &mut response)?;
+
+                Ok(Fetched::Contents(
+                    SourceData::Zip(contents.into()),
+                    Some(validator),
+                ))
+            }
+            _ => Ok(Fetched::Contents(self.read()?, None)),
+        }
+    }
+
+    /// 読み込み元からデータを取得する
+    pub fn read(&self) -> Result<SourceData, IngestError> {
+        let io_error = |path: &PathBuf| {
+            let path = path.display().to_string();
+            move |source| IngestError::Io { path, source }
+        };
+
+        match self {
+            Source::Url(url) => match self.fetch(None)? {
+                Fetched::Contents(contents, _) => Ok(contents),
+                //条件を付けずにリクエストした場合は304が返されることはない
+                Fetched::NotModified => Err(IngestError::HttpStatus {
+                    url: url.clone(),
+                    status: StatusCode::NOT_MODIFIED.as_u16(),
+                }),
+            },
+            Source::LocalZip(path) => {
+                let contents = std::fs::read(path).map_err(io_error(path))?;
+                Ok(SourceData::Zip(contents.into()))
+            }
+            //展開済みのCSVファイルはメモリに読み込まず、読み込むたびにファイルを開き直す
+            Source::LocalCsv(path) => {
+                File::open(path).map_err(io_error(path))?;
+                Ok(SourceData::CsvFile(path.clone()))
+            }
+            //標準入力は一度しか読めないため、内容を保持しておく
+            Source::Stdin => {
+                let mut contents = Vec::new();
+                std::io::stdin()
+                    .read_to_end(&mut contents)
+                    .map_err(|source| IngestError::Io {
+                        path: "stdin".to_string(),
+                        source,
+                    })?;
+                Ok(SourceData::Csv(contents.into()))
+            }
+        }
+    }
+}
+
+/// 読み込み元から取得したデータ
+/// 展開や文字コードの変換を行う前の状態で保持し、読み込むたびに先頭から少しずつ展開・変換する
+#[derive(Debug, Clone)]
+pub enum SourceData {
+    /// zipファイルの内容
+    Zip(Arc<[u8]>),
+    /// 展開済みのCSVファイルの内容
+    Csv(Arc<[u8]>),
+    /// 展開済みのCSVファイルのパス
+    CsvFile(PathBuf),
+}
+
+impl SourceData {
+    /// CSVファイルの内容をUTF-8の文字列として先頭から読み込むリーダーを作成する
+    pub fn open(&self) -> Result<TextReader, IngestError> {
+        let reader: Box<dyn Read + Send> = match self {
+            SourceData::Zip(contents) => open_zip_entry(contents.clone())?,
+            SourceData::Csv(contents) => Box::new(Cursor::new(contents.clone())),
+            SourceData::CsvFile(path) => {
+                Box::new(File::open(path).map_err(|source| IngestError::Io {
+                    path: path.display().to_string(),
+                    source,
+                })?)
+            }
+        };
+
+        return DecodingReader::new(reader).map_err(|source| IngestError::Io {
+            path: self.name(),
+            source,
+        });
+    }
+
+    // エラーメッセージに表示するデータの名前
+    fn name(&self) -> String {
+        match self {
+            SourceData::Zip(_) => "zip contents".to_string(),
+            SourceData::Csv(_) => "stdin".to_string(),
+            SourceData::CsvFile(path) => path.display().to_string(),
+        }
+    }
+}
+
+/// 読み込み元のCSVファイルをUTF-8の文字列として読み込むリーダー
+pub type TextReader = DecodingReader<Box<dyn Read + Send>>;
+
+/// 一回の更新で読み込むデータそれぞれの読み込み元
+#[derive(Debug, Clone)]
+pub struct DataSources {
+    /// 住所の郵便番号データ(ken_all)
+    pub ken_all: Source,
+    /// 大口事業所個別番号データ(jigyosyo)
+    pub jigyosyo: Source,
+    /// 住所の郵便番号データのローマ字版(KEN_ALL_ROME)
+    pub ken_all_rome: Source,
+}
+
+impl DataSources {
+    /// 検証子を保存する際の名前と読み込み元の組を返す
+    fn named(&self) -> [(&'static str, &Source); 3] {
+        [
+            ("ken_all", &self.ken_all),
+            ("jigyosyo", &self.jigyosyo),
+            ("ken_all_rome", &self.ken_all_rome),
+        ]
+    }
+}
+
+/// 読み込み元から取得した各データ
+pub struct SourceContents {
+    pub ken_all: SourceData,
+    pub jigyosyo: SourceData,
+    pub ken_all_rome: SourceData,
+}
+
+/// すべての読み込み元から取得したデータと、次回のために保存する検証子
+pub struct FetchedSources {
+    pub contents: SourceContents,
+    /// 読み込み元の名前と検証子の組の一覧 (URLの読み込み元のみ)
+    pub validators: Vec<(String, CacheValidator)>,
+}
+
+/// HTTPの条件付きリクエストに使用する、前回ダウンロードしたファイルの検証子
+#[derive(Debug, Clone, Default)]
+pub struct CacheValidator {
+    /// 検証子を取得したURL (読み込み元のURLが変わった場合は使用しない)
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// 読み込み元から取得した結果
+pub enum Fetched {
+    /// 前回ダウンロードした時から更新されていない
+    NotModified,
+    /// 取得したデータと、次回の条件付きリクエストに使用する検証子 (URL以外の読み込み元ではNone)
+    Contents(SourceData, Option<CacheValidator>),
+}
+
+/// すべての読み込み元からデータを取得する
+/// 前回の検証子があれば条件付きリクエストを行い、すべてのデータが更新されていなければNoneを返す
+/// 取得したデータと、次回のために保存する検証子の一覧を返す
+pub fn fetch_all(
+    sources: &DataSources,
+    previous_validators: &HashMap<String, CacheValidator>,
+) -> Result<Option<FetchedSources>, IngestError> {
+    let mut fetched_list = Vec::<Fetched>::new();
+    for (name, source) in sources.named() {
+        let validator = match source {
+            Source::Url(url) => previous_validators
+                .get(name)
+                .filter(|validator| &validator.url == url),
+            _ => None,
+        };
+        tracing::info!(name = name, source = ?source, "fetch source");
+        fetched_list.push(source.fetch(validator)?);
+    }
+
+    if fetched_list
+        .iter()
+        .all(|fetched| matches!(fetched, Fetched::NotModified))
+    {
+        return Ok(None);
+    }
+
+    let mut contents_list = Vec::<SourceData>::new();
+    let mut validators = Vec::<(String, CacheValidator)>::new();
+    for ((name, source), fetched) in sources.named().into_iter().zip(fetched_list) {
+        //一部のデータだけが更新されている場合は、更新されていないデータを条件を付けずに取得し直す
+        let fetched = match fetched {
+            Fetched::NotModified => source.fetch(None)?,
+            fetched => fetched,
+        };
+        if let Fetched::Contents(contents, validator) = fetched {
+            contents_list.push(contents);
+            if let Some(validator) = validator {
+                validators.push((name.to_string(), validator));
+            }
+        }
+    }
+
+    //すべての読み込み元の内容を取得しているので、必ず3件になる
+    let [ken_all, jigyosyo, ken_all_rome]: [SourceData; 3] = contents_list
+        .try_into()
+        .expect("contents of all sources are fetched");
+    return Ok(Some(FetchedSources {
+        contents: SourceContents {
+            ken_all,
+            jigyosyo,
+            ken_all_rome,
+        },
+        validators,
+    }));
+}
+
+/// zipファイルをダウンロードする
+/// 検証子が指定されていれば条件付きリクエストを行い、更新されていなければステータスが304のレスポンスを返す
+pub fn download_zip(
+    url: &str,
+    validator: Option<&CacheValidator>,
+) -> Result<Response, IngestError> {
+    let mut request = reqwest::blocking::Client::new().get(url);
+    if let Some(validator) = validator {
+        if let Some(etag) = &validator.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validator.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+
+    let response = request.send().map_err(|source| IngestError::Download {
+        url: url.to_string(),
+        source,
+    })?;
+    if response.status() != StatusCode::OK && response.status() != StatusCode::NOT_MODIFIED {
+        return Err(IngestError::HttpStatus {
+            url: url.to_string(),
+            status: response.status().as_u16(),
+        });
+    }
+
+    return Ok(response);
+}
+
+fn response_to_bytes(url: &str, response: &mut Response) -> Result<Vec<u8>, IngestError> {
+    let mut buf = Vec::new();
+    response
+        .copy_to(&mut buf)
+        .map_err(|source| IngestError::Download {
+            url: url.to_string(),
+            source,
+        })?;
+
+    return Ok(buf);
+}
+
+// zipファイルに含まれるファイルを展開しながら読み込むリーダーを作成する
+// ZipArchiveから取得したファイルはアーカイブを借用するため、圧縮データの位置だけを取得して自前で展開する
+fn open_zip_entry(contents: Arc<[u8]>) -> Result<Box<dyn Read + Send>, IngestError> {
+    let mut archive = ZipArchive::new(Cursor::new(contents.clone()))?;
+
+    // Zipファイルに一つだけファイルが含まれているはずなので、そのファイルを取得
+    let zip_file = archive.by_index_raw(0)?;
+    let start = zip_file.data_start();
+    let compressed_size = zip_file.compressed_size();
+    let compression = zip_file.compression();
+    if start + compressed_size > contents.len() as u64 {
+        return Err(ZipError::InvalidArchive("compressed data is out of the archive").into());
+    }
+
+    let mut cursor = Cursor::new(contents);
+    cursor.set_position(start);
+    let compressed = cursor.take(compressed_size);
+    match compression {
+        CompressionMethod::Stored => Ok(Box::new(compressed)),
+        CompressionMethod::Deflated => Ok(Box::new(DeflateDecoder::new(compressed))),
+        _ => Err(ZipError::UnsupportedArchive("unsupported compression method").into()),
+    }
+}
+
+// 文字コードを判定するために先頭から読み込むバイト数
+const SNIFF_LENGTH: usize = 64 * 1024;
+// 文字コードの変換に使用するバッファのサイズ
+const DECODE_BUFFER_SIZE: usize = 16 * 1024;
+
+/// UTF-8版とShift-JIS版のどちらのファイルも、少しずつUTF-8に変換しながら読み込むリーダー
+/// ファイル全体を文字列に変換せずに済むように、encoding_rsのDecoderで逐次変換する
+pub struct DecodingReader<R> {
+    inner: R,
+    decoder: Decoder,
+    // 変換前のデータと、その中のまだ変換していない範囲
+    input: Vec<u8>,
+    input_start: usize,
+    input_end: usize,
+    // 変換後のデータと、その中のまだ読み出していない範囲
+    output: Vec<u8>,
+    output_start: usize,
+    output_end: usize,
+    // 元のリーダーを最後まで読み込んだらtrue
+    eof: bool,
+    // 変換が最後まで完了したらtrue
+    finished: bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    /// ファイルの先頭を読み込んで文字コードを判定し、リーダーを作成する
+    /// 先頭がUTF-8として正しい内容であればUTF-8、そうでなければShift-JISとして変換する (BOMは取り除かれる)
+    pub fn new(mut inner: R) -> std::io::Result<DecodingReader<R>> {
+        let mut input = vec![0; SNIFF_LENGTH.max(DECODE_BUFFER_SIZE)];
+        let mut input_end = 0;
+        let mut eof = false;
+        while input_end < SNIFF_LENGTH {
+            let read = inner.read(&mut input[input_end..SNIFF_LENGTH])?;
+            if read == 0 {
+                eof = true;
+                break;
+            }
+            input_end += read;
+        }
+
+        //末尾で文字が途切れているだけであれば、UTF-8として正しい内容とみなす
+        let is_utf8 = match std::str::from_utf8(&input[..input_end]) {
+            Ok(_) => true,
+            Err(err) => err.error_len().is_none(),
+        };
+        let decoder = if is_utf8 {
+            UTF_8.new_decoder()
+        } else {
+            SHIFT_JIS.new_decoder()
+        };
+
+        return Ok(DecodingReader {
+            inner,
+            decoder,
+            input,
+            input_start: 0,
+            input_end,
+            output: vec![0; DECODE_BUFFER_SIZE],
+            output_start: 0,
+            output_end: 0,
+            eof,
+            finished: false,
+        });
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            //変換済みのデータが残っていれば、それを返す
+            if self.output_start < self.output_end {
+                let length = buf.len().min(self.output_end - self.output_start);
+                buf[..length]
+                    .copy_from_slice(&self.output[self.output_start..self.output_start + length]);
+                self.output_start += length;
+                return Ok(length);
+            }
+            if self.finished {
+                return Ok(0);
+            }
+
+            //変換前のデータをすべて変換し終えていれば、続きを読み込む
+            if self.input_start == self.input_end && !self.eof {
+                self.input_start = 0;
+                self.input_end = self.inner.read(&mut self.input)?;
+                if self.input_end == 0 {
+                    self.eof = true;
+                }
+            }
+
+            let (result, read, written) = self.decoder.decode_to_utf8_without_replacement(
+                &self.input[self.input_start..self.input_end],
+                &mut self.output,
+                self.eof,
+            );
+            self.input_start += read;
+            self.output_start = 0;
+            self.output_end = written;
+            match result {
+                DecoderResult::InputEmpty => {
+                    if self.eof {
+                        self.finished = true;
+                    }
+                }
+                DecoderResult::OutputFull => {}
+                //UTF-8としてもShift-JISとしても正しくない内容が含まれている
+                //CSVのリーダーを経由しても判別できるように、IngestErrorを包んで返す
+                DecoderResult::Malformed(_, _) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        IngestError::Encoding,
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_source_spec() {
+        let cases = [
+            (
+                "https://www.post.japanpost.jp/zipcode/dl/kogaki/zip/ken_all.zip",
+                Source::Url(
+                    "https://www.post.japanpost.jp/zipcode/dl/kogaki/zip/ken_all.zip".to_string(),
+                ),
+            ),
+            (
+                "http://localhost:8080/ken_all.zip",
+                Source::Url("http://localhost:8080/ken_all.zip".to_string()),
+            ),
+            ("-", Source::Stdin),
+            ("stdin", Source::Stdin),
+            (
+                "/tmp/ken_all.zip",
+                Source::LocalZip(PathBuf::from("/tmp/ken_all.zip")),
+            ),
+            (
+                "/tmp/KEN_ALL.ZIP",
+                Source::LocalZip(PathBuf::from("/tmp/KEN_ALL.ZIP")),
+            ),
+            (
+                "file:///tmp/ken_all.zip",
+                Source::LocalZip(PathBuf::from("/tmp/ken_all.zip")),
+            ),
+            (
+                "/tmp/KEN_ALL.CSV",
+                Source::LocalCsv(PathBuf::from("/tmp/KEN_ALL.CSV")),
+            ),
+            (
+                "file:///tmp/utf_ken_all.csv",
+                Source::LocalCsv(PathBuf::from("/tmp/utf_ken_all.csv")),
+            ),
+            ("ken_all", Source::LocalCsv(PathBuf::from("ken_all"))),
+        ];
+
+        for (spec, expected) in cases {
+            assert_eq!(Source::from_spec(spec), expected, "spec: {}", spec);
+        }
+    }
+
+    // 1バイトずつしか読み込めないリーダー (文字の途中で読み込みが途切れる場合の確認用)
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.split_first() {
+                Some((first, rest)) if !buf.is_empty() => {
+                    buf[0] = *first;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    fn decode<R: Read>(reader: R) -> std::io::Result<String> {
+        let mut text = String::new();
+        DecodingReader::new(reader)?.read_to_string(&mut text)?;
+        return Ok(text);
+    }
+
+    #[test]
+    fn decode_utf8_and_strip_bom() {
+        let text = decode("\u{FEFF}01101,\"ホッカイドウ\",\"北海道\"".as_bytes()).unwrap();
+
+        assert_eq!(text, "01101,\"ホッカイドウ\",\"北海道\"");
+    }
+
+    #[test]
+    fn decode_shift_jis() {
+        let (bytes, _, had_errors) = SHIFT_JIS.encode("01101,\"ﾎｯｶｲﾄﾞｳ\",\"北海道\"");
+        assert!(!had_errors);
+        assert!(std::str::from_utf8(&bytes).is_err());
+
+        assert_eq!(decode(&bytes[..]).unwrap(), "01101,\"ﾎｯｶｲﾄﾞｳ\",\"北海道\"");
+    }
+
+    #[test]
+    fn decode_characters_split_across_reads() {
+        let utf8 = "札幌市中央区";
+        assert_eq!(decode(ByteReader(utf8.as_bytes())).unwrap(), utf8);
+
+        let (shift_jis, _, _) = SHIFT_JIS.encode("札幌市中央区");
+        assert_eq!(decode(ByteReader(&shift_jis)).unwrap(), utf8);
+    }
+
+    #[test]
+    fn malformed_contents_are_encoding_error() {
+        // Shift-JISの2バイト文字の1バイト目で終わっている
+        let err = decode(&[0x30, 0x81][..]).unwrap_err();
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(matches!(
+            err.into_inner().unwrap().downcast_ref::<IngestError>(),
+            Some(IngestError::Encoding)
+        ));
+    }
+}
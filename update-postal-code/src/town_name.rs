@@ -0,0 +1,416 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::postal_code_record::PostalCodeRecord;
+
+// 範囲を展開する際の最大件数 (誤った解釈で大量の町域が作られないようにする)
+const MAX_RANGE_LENGTH: u32 = 100;
+
+// 町域名に含まれる数字の表記
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NumberStyle {
+    // 半角数字
+    Hankaku,
+    // 全角数字
+    Zenkaku,
+    // 漢数字
+    Kanji,
+}
+
+/// 「、」で列挙された町域名と、「～」で範囲指定された町域名を個別の町域に展開する
+/// 例: 「一区～三区」→「一区」「二区」「三区」
+/// 地割を含む町域名は展開前に町名と地割番号に分けるため、「越中畑６４地割～越中畑６６地割」は
+/// 地割番号６４～６６を持つ一つの町域「越中畑」になる
+pub fn expand_town_names(postal_code_record_list: Vec<PostalCodeRecord>) -> Vec<PostalCodeRecord> {
+    let mut expanded_list = Vec::<PostalCodeRecord>::with_capacity(postal_code_record_list.len());
+    for mut record in postal_code_record_list {
+        //地割を含む町域名は、地割を取り除いた町名と地割番号に分ける
+        normalize_chiwari(&mut record);
+
+        let towns = expand_town(&record.town, &['、'], &['～']);
+        if towns.len() <= 1 {
+            expanded_list.push(record);
+            continue;
+        }
+
+        //カナも同じように展開する (カナの範囲指定は「－」で表されている)
+        let town_kana_list = expand_town(&record.town_kana, &['、'], &['－', '～']);
+        let town_count = towns.len();
+        for (index, town) in towns.into_iter().enumerate() {
+            let mut expanded = record.clone();
+            expanded.town = town;
+            //ローマ字表記は展開前の町域名に対するものなので、個別の町域には使えない
+            expanded.town_roma = "".to_string();
+            //カナの展開結果の数が一致しない場合は、どの町域に対応するか分からないので展開前のカナをそのまま使う
+            if town_kana_list.len() == town_count {
+                expanded.town_kana = town_kana_list[index].clone();
+            }
+            expanded_list.push(expanded);
+        }
+    }
+
+    return expanded_list;
+}
+
+/// 「越中畑６４地割～越中畑６６地割」「種市第１５地割～第２１地割」のような地割を含む町域名を
+/// 地割を除いた町名と地割番号の一覧に分ける
+/// 括弧内の注記に書かれた「第４０地割～第４５地割」のような地割も地割番号として取り出す
+fn normalize_chiwari(record: &mut PostalCodeRecord) {
+    if record.town.contains("地割") {
+        if let Some((base_town, chiwari)) = parse_chiwari(&record.town) {
+            if !base_town.is_empty() {
+                record.town = base_town;
+                //ローマ字表記は地割の部分の表記が分からないため、町名のみを取り出せない
+                record.town_roma = "".to_string();
+                //カナも「ダイ１５チワリ」のような地割の部分を取り除く
+                static KANA_REGEXP: OnceLock<Regex> = OnceLock::new();
+                let kana_regexp = KANA_REGEXP
+                    .get_or_init(|| Regex::new(r"^(.*?)(ダイ)?[0-9０-９]+チワリ").unwrap());
+                if let Some(captures) = kana_regexp.captures(&record.town_kana) {
+                    record.town_kana = captures[1].to_string();
+                }
+            }
+            record.chiwari.extend(chiwari);
+        }
+    }
+
+    if let Some(annotation) = &mut record.annotation {
+        let mut others = Vec::<String>::new();
+        for part in std::mem::take(&mut annotation.others) {
+            if !part.contains("地割") {
+                others.push(part);
+                continue;
+            }
+            match parse_chiwari(&part) {
+                Some((base_town, chiwari)) if base_town.is_empty() => {
+                    record.chiwari.extend(chiwari)
+                }
+                _ => others.push(part),
+            }
+        }
+        annotation.others = others;
+    }
+
+    record.chiwari.sort();
+    record.chiwari.dedup();
+}
+
+// 地割を含む文字列を解析して、地割を除いた町名と地割番号の一覧を返す
+// 「第４０地割「５７番地１２５、１７６を除く」」のような地割内の番地の注記は無視する
+// 地割として解釈できない部分があればNoneを返す
+fn parse_chiwari(text: &str) -> Option<(String, Vec<u32>)> {
+    //正規表現は住所レコードごとに作り直さないように、最初に使用する際に一度だけ作成する
+    static NOTE_REGEXP: OnceLock<Regex> = OnceLock::new();
+    static CHIWARI_REGEXP: OnceLock<Regex> = OnceLock::new();
+    let note_regexp = NOTE_REGEXP.get_or_init(|| Regex::new(r"「.*?」").unwrap());
+    let chiwari_regexp = CHIWARI_REGEXP.get_or_init(|| {
+        Regex::new(r"^(.*?)第?([0-9０-９]+|[〇一二三四五六七八九十百千]+)地割$").unwrap()
+    });
+
+    let text = note_regexp.replace_all(text, "");
+    let mut base_town: Option<String> = None;
+    let mut chiwari = Vec::<u32>::new();
+    for part in text.split('、') {
+        let mut numbers = Vec::<u32>::new();
+        for side in part.split('～') {
+            let captures = chiwari_regexp.captures(side.trim())?;
+            let number_text = &captures[2];
+            let style = number_style(number_text.chars().next()?)?;
+            numbers.push(parse_number(number_text, style)?);
+
+            //「種市第１５地割～第２１地割」のように、二つ目以降の町名は省略されることがある
+            let town = &captures[1];
+            match &base_town {
+                None => base_town = Some(town.to_string()),
+                Some(base_town) if town.is_empty() || town == base_town => {}
+                Some(_) => return None,
+            }
+        }
+
+        match numbers[..] {
+            [number] => chiwari.push(number),
+            [start, end] if start <= end && end - start < MAX_RANGE_LENGTH => {
+                chiwari.extend(start..=end)
+            }
+            _ => return None,
+        }
+    }
+
+    return Some((base_town.unwrap_or_default(), chiwari));
+}
+
+// 町域名を区切り文字で分割し、範囲指定があれば展開する
+fn expand_town(town: &str, separators: &[char], range_separators: &[char]) -> Vec<String> {
+    let mut towns = Vec::<String>::new();
+    for part in town.split(|c| separators.contains(&c)) {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+
+        match expand_range(part, range_separators) {
+            Some(expanded) => towns.extend(expanded),
+            None => towns.push(part.to_string()),
+        }
+    }
+
+    return towns;
+}
+
+// 「第１地割～第５地割」「１～３丁目」のような範囲指定を展開する
+// 範囲として解釈できない場合はNoneを返す
+fn expand_range(part: &str, range_separators: &[char]) -> Option<Vec<String>> {
+    let mut sides = part.split(|c| range_separators.contains(&c));
+    let (start, end) = match (sides.next(), sides.next(), sides.next()) {
+        (Some(start), Some(end), None) => (start, end),
+        _ => return None,
+    };
+
+    let (start_prefix, start_number, start_suffix, style) = split_number(start)?;
+    let (end_prefix, end_number, end_suffix, _) = split_number(end)?;
+
+    //「１～３丁目」のように片側の接頭辞・接尾辞が省略されている場合は、もう片側のものを使う
+    let prefix = if end_prefix.is_empty() || start_prefix == end_prefix {
+        start_prefix
+    } else if start_prefix.is_empty() {
+        end_prefix
+    } else {
+        return None;
+    };
+    let suffix = if start_suffix.is_empty() || start_suffix == end_suffix {
+        end_suffix
+    } else if end_suffix.is_empty() {
+        start_suffix
+    } else {
+        return None;
+    };
+
+    if start_number > end_number || end_number - start_number >= MAX_RANGE_LENGTH {
+        return None;
+    }
+    //漢数字は万以上の位を扱わない
+    if style == NumberStyle::Kanji && end_number >= 10000 {
+        return None;
+    }
+
+    return Some(
+        (start_number..=end_number)
+            .map(|number| format!("{}{}{}", prefix, format_number(number, style), suffix))
+            .collect(),
+    );
+}
+
+// 文字列の最後に現れる数字の並びで、接頭辞・数値・接尾辞に分割する
+fn split_number(s: &str) -> Option<(&str, u32, &str, NumberStyle)> {
+    let chars = s.char_indices().collect::<Vec<_>>();
+    let last = chars
+        .iter()
+        .rposition(|(_, c)| number_style(*c).is_some())?;
+    let style = number_style(chars[last].1).unwrap();
+
+    let mut first = last;
+    while first > 0 && number_style(chars[first - 1].1) == Some(style) {
+        first -= 1;
+    }
+
+    let start_index = chars[first].0;
+    let end_index = chars[last].0 + chars[last].1.len_utf8();
+    let number = parse_number(&s[start_index..end_index], style)?;
+
+    return Some((&s[..start_index], number, &s[end_index..], style));
+}
+
+fn number_style(c: char) -> Option<NumberStyle> {
+    match c {
+        '0'..='9' => Some(NumberStyle::Hankaku),
+        '０'..='９' => Some(NumberStyle::Zenkaku),
+        '〇' | '一' | '二' | '三' | '四' | '五' | '六' | '七' | '八' | '九' | '十' | '百'
+        | '千' => Some(NumberStyle::Kanji),
+        _ => None,
+    }
+}
+
+fn parse_number(s: &str, style: NumberStyle) -> Option<u32> {
+    match style {
+        NumberStyle::Hankaku => s.parse().ok(),
+        NumberStyle::Zenkaku => s
+            .chars()
+            .map(|c| char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap())
+            .collect::<String>()
+            .parse()
+            .ok(),
+        NumberStyle::Kanji => parse_kanji_number(s),
+    }
+}
+
+// 「二十三」「百五」のような漢数字を数値に変換する
+fn parse_kanji_number(s: &str) -> Option<u32> {
+    let mut total: u32 = 0;
+    let mut current: u32 = 0;
+    for c in s.chars() {
+        let unit = match c {
+            '十' => 10,
+            '百' => 100,
+            '千' => 1000,
+            _ => {
+                let digit = KANJI_DIGITS.iter().position(|d| *d == c)? as u32;
+                current = current.checked_mul(10)?.checked_add(digit)?;
+                continue;
+            }
+        };
+        //「十」のように係数が省略されている場合は1とみなす
+        total = total.checked_add(current.max(1).checked_mul(unit)?)?;
+        current = 0;
+    }
+
+    return Some(total + current);
+}
+
+const KANJI_DIGITS: [char; 10] = ['〇', '一', '二', '三', '四', '五', '六', '七', '八', '九'];
+
+// 数値を元の表記に合わせた文字列に変換する
+fn format_number(number: u32, style: NumberStyle) -> String {
+    match style {
+        NumberStyle::Hankaku => number.to_string(),
+        NumberStyle::Zenkaku => number
+            .to_string()
+            .chars()
+            .map(|c| char::from_u32(c as u32 - '0' as u32 + '０' as u32).unwrap())
+            .collect(),
+        NumberStyle::Kanji => format_kanji_number(number),
+    }
+}
+
+// 数値を「二十三」のような漢数字に変換する
+fn format_kanji_number(number: u32) -> String {
+    if number == 0 {
+        return KANJI_DIGITS[0].to_string();
+    }
+
+    let mut result = String::new();
+    let mut rest = number;
+    for (unit, unit_char) in [(1000, '千'), (100, '百'), (10, '十')] {
+        let digit = rest / unit;
+        if digit > 0 {
+            //「十」「百」のように係数が1の場合は省略する
+            if digit > 1 {
+                result.push(KANJI_DIGITS[digit as usize]);
+            }
+            result.push(unit_char);
+        }
+        rest %= unit;
+    }
+    if rest > 0 {
+        result.push(KANJI_DIGITS[rest as usize]);
+    }
+
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postal_code_record::Changed;
+
+    // 町域名と町域名カナだけを指定した住所レコードを作成する
+    fn record(town: &str, town_kana: &str) -> PostalCodeRecord {
+        let mut record = PostalCodeRecord::new(
+            "03366".to_string(),
+            "0295503".to_string(),
+            "イワテケン".to_string(),
+            "ワガグンニシワガマチ".to_string(),
+            town_kana.to_string(),
+            "岩手県".to_string(),
+            "和賀郡西和賀町".to_string(),
+            town.to_string(),
+            false,
+            false,
+            false,
+            false,
+            Changed::NoChange,
+        );
+        record.town_roma = "ROMA".to_string();
+        return record;
+    }
+
+    // 展開後の町域名と町域名カナの組を取得する
+    fn expand(town: &str, town_kana: &str) -> Vec<(String, String)> {
+        return expand_town_names(vec![record(town, town_kana)])
+            .into_iter()
+            .map(|record| (record.town, record.town_kana))
+            .collect();
+    }
+
+    #[test]
+    fn expand_list() {
+        let towns = expand("長原、寺野", "ナガハラ、テラノ");
+
+        assert_eq!(
+            towns,
+            vec![
+                ("長原".to_string(), "ナガハラ".to_string()),
+                ("寺野".to_string(), "テラノ".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_kanji_range() {
+        let towns = expand("一区～三区", "１ク－３ク");
+
+        assert_eq!(
+            towns,
+            vec![
+                ("一区".to_string(), "１ク".to_string()),
+                ("二区".to_string(), "２ク".to_string()),
+                ("三区".to_string(), "３ク".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_zenkaku_range() {
+        // 接尾辞が片側にしかない範囲指定も、もう片側の接尾辞を補って展開する
+        let records = expand_town_names(vec![record("１～３丁目", "１－３チョウメ")]);
+
+        let towns = records
+            .iter()
+            .map(|record| (record.town.as_str(), record.town_kana.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            towns,
+            vec![
+                ("１丁目", "１チョウメ"),
+                ("２丁目", "２チョウメ"),
+                ("３丁目", "３チョウメ")
+            ]
+        );
+        // 展開前の町域名のローマ字表記は使えない
+        assert!(records.iter().all(|record| record.town_roma.is_empty()));
+    }
+
+    #[test]
+    fn keep_kana_when_counts_differ() {
+        // カナの展開結果の数が町域名と一致しなければ、展開前のカナを使う
+        let towns = expand("長原、寺野、上野", "ナガハラ、テラノ");
+
+        assert_eq!(towns.len(), 3);
+        assert!(towns
+            .iter()
+            .all(|(_, town_kana)| town_kana == "ナガハラ、テラノ"));
+    }
+
+    #[test]
+    fn chiwari_range_is_not_expanded_into_towns() {
+        let records = expand_town_names(vec![record(
+            "越中畑６４地割～越中畑６６地割",
+            "エッチュウハタ６４チワリ－エッチュウハタ６６チワリ",
+        )]);
+
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].town, "越中畑");
+        assert_eq!(records[0].town_kana, "エッチュウハタ");
+        assert_eq!(records[0].chiwari, vec![64, 65, 66]);
+    }
+}
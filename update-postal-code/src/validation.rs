@@ -0,0 +1,433 @@
+use std::collections::BTreeMap;
+
+use crate::error::IngestError;
+use crate::postal_code_record::PostalCodeRecord;
+
+// ルールごとにレポートに含める違反の例の最大件数
+const MAX_SAMPLES: usize = 10;
+// カナの項目の形式が不正な住所レコードの許容件数のデフォルト値
+// 元データの表記揺れで少数の違反が出ることがあるため、形式の違反よりも緩くしておく
+pub const DEFAULT_MAX_INVALID_KANA: usize = 100;
+
+/// 住所レコードの検証ルール
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValidationRule {
+    /// 郵便番号が半角数字7桁でない
+    PostalCode,
+    /// 全国地方公共団体コードが半角数字5桁でない
+    NationalLocalGovernmentCode,
+    /// カナの項目にカタカナ以外の文字が含まれている
+    Kana,
+    /// CSVの行の列が足りない、または項目の値が想定外で住所レコードにできない
+    RowShape,
+}
+
+impl ValidationRule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidationRule::PostalCode => "postal_code",
+            ValidationRule::NationalLocalGovernmentCode => "national_local_government_code",
+            ValidationRule::Kana => "kana",
+            ValidationRule::RowShape => "row_shape",
+        }
+    }
+}
+
+/// 検証ルールごとの違反の許容件数 (これを超えると更新を中止する)
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationThresholds {
+    pub max_invalid_postal_code: usize,
+    pub max_invalid_national_local_government_code: usize,
+    pub max_invalid_kana: usize,
+    pub max_invalid_row_shape: usize,
+}
+
+impl Default for ValidationThresholds {
+    fn default() -> Self {
+        ValidationThresholds {
+            max_invalid_postal_code: 0,
+            max_invalid_national_local_government_code: 0,
+            max_invalid_kana: DEFAULT_MAX_INVALID_KANA,
+            max_invalid_row_shape: 0,
+        }
+    }
+}
+
+impl ValidationThresholds {
+    /// 環境変数から許容件数を取得する (未指定のルールはデフォルト値を使用する)
+    pub fn from_env() -> Result<ValidationThresholds, IngestError> {
+        return ValidationThresholds::from_lookup(|name| std::env::var(name).ok());
+    }
+
+    // 設定値の名前から値を取得する関数を使って許容件数を取得する
+    // 数値として解釈できない値が設定されていれば、設定値のエラーを返す
+    fn from_lookup(
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<ValidationThresholds, IngestError> {
+        let defaults = ValidationThresholds::default();
+        let max_invalid = |name: &str, default: usize| match lookup(name) {
+            Some(value) => value.parse::<usize>().map_err(|_| IngestError::Config {
+                name: name.to_string(),
+                value,
+            }),
+            None => Ok(default),
+        };
+
+        return Ok(ValidationThresholds {
+            max_invalid_postal_code: max_invalid(
+                "VALIDATION_MAX_INVALID_POSTAL_CODE",
+                defaults.max_invalid_postal_code,
+            )?,
+            max_invalid_national_local_government_code: max_invalid(
+                "VALIDATION_MAX_INVALID_NATIONAL_LOCAL_GOVERNMENT_CODE",
+                defaults.max_invalid_national_local_government_code,
+            )?,
+            max_invalid_kana: max_invalid(
+                "VALIDATION_MAX_INVALID_KANA",
+                defaults.max_invalid_kana,
+            )?,
+            max_invalid_row_shape: max_invalid(
+                "VALIDATION_MAX_INVALID_ROW_SHAPE",
+                defaults.max_invalid_row_shape,
+            )?,
+        });
+    }
+
+    fn max_invalid(&self, rule: ValidationRule) -> usize {
+        match rule {
+            ValidationRule::PostalCode => self.max_invalid_postal_code,
+            ValidationRule::NationalLocalGovernmentCode => {
+                self.max_invalid_national_local_government_code
+            }
+            ValidationRule::Kana => self.max_invalid_kana,
+            ValidationRule::RowShape => self.max_invalid_row_shape,
+        }
+    }
+}
+
+/// 住所レコードの検証結果のレポート
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Default)]
+pub struct ValidationReport {
+    // 検証した住所レコードの件数
+    pub checked_count: usize,
+    // 違反の件数が許容件数を超えたルールがあればtrue
+    pub failed: bool,
+    // ルールごとの違反の件数と例 (違反があったルールのみ)
+    pub rules: BTreeMap<String, RuleReport>,
+}
+
+impl ValidationReport {
+    /// 違反の件数が許容件数を超えたルールの名前
+    pub fn failed_rules(&self) -> Vec<String> {
+        return self
+            .rules
+            .iter()
+            .filter(|(_, rule)| rule.count > rule.max_invalid)
+            .map(|(name, _)| name.clone())
+            .collect();
+    }
+}
+
+/// 検証ルール単位の違反の件数と例
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Default)]
+pub struct RuleReport {
+    pub count: usize,
+    pub max_invalid: usize,
+    pub samples: Vec<ValidationSample>,
+}
+
+/// 検証ルールに違反した住所レコードの例
+#[derive(serde::Deserialize, serde::Serialize, std::fmt::Debug, Default)]
+pub struct ValidationSample {
+    // 行の形式の違反では住所レコードを作成できないため空になる
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub national_local_government_code: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub postal_code: String,
+    // 違反した項目の名前と値
+    pub field: String,
+    pub value: String,
+    // 行の形式の違反の場合のみ、違反したファイルと行番号を持つ
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row: Option<u64>,
+}
+
+/// 住所レコードを順に検証し、ルールごとの違反を集計する
+pub struct Validator {
+    thresholds: ValidationThresholds,
+    report: ValidationReport,
+}
+
+impl Validator {
+    pub fn new(thresholds: ValidationThresholds) -> Validator {
+        Validator {
+            thresholds,
+            report: ValidationReport::default(),
+        }
+    }
+
+    /// 住所レコードを検証する
+    /// 一つのレコードが同じルールに複数違反していても、違反の件数は一件として数える
+    pub fn check(&mut self, record: &PostalCodeRecord) {
+        self.report.checked_count += 1;
+
+        if !is_digits(&record.postal_code, 7) {
+            self.add(
+                ValidationRule::PostalCode,
+                record,
+                "postal_code",
+                &record.postal_code,
+            );
+        }
+
+        if !is_digits(&record.national_local_government_code, 5) {
+            self.add(
+                ValidationRule::NationalLocalGovernmentCode,
+                record,
+                "national_local_government_code",
+                &record.national_local_government_code,
+            );
+        }
+
+        let mut kana_fields = vec![
+            ("prefecture_kana", &record.prefecture_kana),
+            ("city_kana", &record.city_kana),
+            ("town_kana", &record.town_kana),
+        ];
+        if let Some(business) = &record.business {
+            kana_fields.push(("business_name_kana", &business.name_kana));
+        }
+        if let Some((field, value)) = kana_fields.into_iter().find(|(_, value)| !is_kana(value)) {
+            self.add(ValidationRule::Kana, record, field, value);
+        }
+    }
+
+    /// 読み飛ばした不正な行を、行の形式の違反として記録する
+    pub fn check_row_error(&mut self, err: &IngestError) {
+        self.report.checked_count += 1;
+
+        self.add_sample(
+            ValidationRule::RowShape,
+            ValidationSample {
+                field: "row".to_string(),
+                value: err.to_string(),
+                file: err.file().map(|file| file.to_string()),
+                row: err.row(),
+                ..Default::default()
+            },
+        );
+    }
+
+    /// 検証を終えてレポートを取得する
+    /// 違反の件数が許容件数を超えたルールがあれば、レポートのfailedがtrueになる
+    pub fn finish(mut self) -> ValidationReport {
+        self.report.failed = self
+            .report
+            .rules
+            .values()
+            .any(|rule_report| rule_report.count > rule_report.max_invalid);
+
+        for (rule, rule_report) in &self.report.rules {
+            tracing::warn!(
+                rule = rule,
+                count = rule_report.count,
+                max_invalid = rule_report.max_invalid,
+                "invalid postal code records"
+            );
+        }
+
+        return self.report;
+    }
+
+    // 住所レコードの違反を記録する
+    fn add(&mut self, rule: ValidationRule, record: &PostalCodeRecord, field: &str, value: &str) {
+        self.add_sample(
+            rule,
+            ValidationSample {
+                national_local_government_code: record.national_local_government_code.clone(),
+                postal_code: record.postal_code.clone(),
+                field: field.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            },
+        );
+    }
+
+    // 違反を記録し、例の最大件数までは違反の内容も記録する
+    fn add_sample(&mut self, rule: ValidationRule, sample: ValidationSample) {
+        let max_invalid = self.thresholds.max_invalid(rule);
+        let rule_report = self
+            .report
+            .rules
+            .entry(rule.as_str().to_string())
+            .or_insert_with(|| RuleReport {
+                max_invalid,
+                ..Default::default()
+            });
+
+        rule_report.count += 1;
+        if rule_report.samples.len() < MAX_SAMPLES {
+            rule_report.samples.push(sample);
+        }
+    }
+}
+
+// 指定された桁数の半角数字か判定する
+fn is_digits(value: &str, length: usize) -> bool {
+    value.len() == length && value.chars().all(|c| c.is_ascii_digit())
+}
+
+// 全角カタカナと、カナの項目に含まれる全角英数記号・句読点のみで構成されているか判定する
+// 空文字は町域名がない場合などにあり得るため、正しい値とみなす
+fn is_kana(value: &str) -> bool {
+    value.chars().all(|c| {
+        // 全角カタカナ (「ー」「・」を含む)
+        ('\u{30A0}'..='\u{30FF}').contains(&c)
+            // 全角英数記号 (半角英数記号から変換されたもの)
+            || ('\u{FF01}'..='\u{FF5E}').contains(&c)
+            // 全角スペースと句読点・括弧
+            || "\u{3000}、。「」".contains(c)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::postal_code_record::Changed;
+
+    fn record(postal_code: &str, code: &str, town_kana: &str) -> PostalCodeRecord {
+        return PostalCodeRecord::new(
+            code.to_string(),
+            postal_code.to_string(),
+            "ホッカイドウ".to_string(),
+            "サッポロシチュウオウク".to_string(),
+            town_kana.to_string(),
+            "北海道".to_string(),
+            "札幌市中央区".to_string(),
+            "大通西".to_string(),
+            false,
+            false,
+            true,
+            false,
+            Changed::NoChange,
+        );
+    }
+
+    #[test]
+    fn valid_record_has_no_violation() {
+        let mut validator = Validator::new(ValidationThresholds::default());
+        validator.check(&record(
+            "0600042",
+            "01101",
+            "オオドオリニシ（１－１９チョウメ）",
+        ));
+        let report = validator.finish();
+
+        assert_eq!(report.checked_count, 1);
+        assert!(report.rules.is_empty());
+        assert!(!report.failed);
+    }
+
+    #[test]
+    fn invalid_postal_code_fails() {
+        let mut validator = Validator::new(ValidationThresholds::default());
+        validator.check(&record("060004", "01101", "オオドオリニシ"));
+        validator.check(&record("０６０００４２", "01101", "オオドオリニシ"));
+        let report = validator.finish();
+
+        let rule = &report.rules["postal_code"];
+        assert_eq!(rule.count, 2);
+        assert_eq!(rule.samples[0].field, "postal_code");
+        assert_eq!(rule.samples[0].value, "060004");
+        assert!(report.failed);
+        assert_eq!(report.failed_rules(), vec!["postal_code"]);
+    }
+
+    #[test]
+    fn invalid_national_local_government_code_fails() {
+        let mut validator = Validator::new(ValidationThresholds::default());
+        validator.check(&record("0600042", "1101", "オオドオリニシ"));
+        let report = validator.finish();
+
+        let rule = &report.rules["national_local_government_code"];
+        assert_eq!(rule.count, 1);
+        assert_eq!(rule.samples[0].postal_code, "0600042");
+        assert!(report.failed);
+    }
+
+    #[test]
+    fn invalid_kana_is_counted_once_per_record() {
+        let mut validator = Validator::new(ValidationThresholds::default());
+        let mut invalid = record("0600042", "01101", "ｵｵﾄﾞｵﾘﾆｼ");
+        invalid.city_kana = "さっぽろし".to_string();
+        validator.check(&invalid);
+        let report = validator.finish();
+
+        let rule = &report.rules["kana"];
+        assert_eq!(rule.count, 1);
+        assert_eq!(rule.samples[0].field, "city_kana");
+        // カナの違反はデフォルトでは許容件数以内
+        assert_eq!(rule.max_invalid, DEFAULT_MAX_INVALID_KANA);
+        assert!(!report.failed);
+    }
+
+    #[test]
+    fn fail_only_when_count_exceeds_threshold() {
+        let thresholds = ValidationThresholds {
+            max_invalid_kana: 1,
+            ..Default::default()
+        };
+
+        let mut validator = Validator::new(thresholds);
+        validator.check(&record("0600042", "01101", "ｵｵﾄﾞｵﾘﾆｼ"));
+        assert!(!validator.finish().failed);
+
+        let mut validator = Validator::new(thresholds);
+        validator.check(&record("0600042", "01101", "ｵｵﾄﾞｵﾘﾆｼ"));
+        validator.check(&record("0600043", "01101", "ｵｵﾄﾞｵﾘﾋｶﾞｼ"));
+        let report = validator.finish();
+        assert!(report.failed);
+        assert_eq!(report.failed_rules(), vec!["kana"]);
+    }
+
+    #[test]
+    fn samples_are_limited() {
+        let mut validator = Validator::new(ValidationThresholds::default());
+        for _ in 0..MAX_SAMPLES + 5 {
+            validator.check(&record("", "01101", "オオドオリニシ"));
+        }
+        let report = validator.finish();
+
+        assert_eq!(report.rules["postal_code"].count, MAX_SAMPLES + 5);
+        assert_eq!(report.rules["postal_code"].samples.len(), MAX_SAMPLES);
+    }
+
+    #[test]
+    fn thresholds_from_lookup() {
+        let thresholds = ValidationThresholds::from_lookup(|name| match name {
+            "VALIDATION_MAX_INVALID_POSTAL_CODE" => Some("3".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(thresholds.max_invalid_postal_code, 3);
+        assert_eq!(thresholds.max_invalid_kana, DEFAULT_MAX_INVALID_KANA);
+    }
+
+    #[test]
+    fn invalid_threshold_is_config_error() {
+        let err = ValidationThresholds::from_lookup(|name| match name {
+            "VALIDATION_MAX_INVALID_KANA" => Some("many".to_string()),
+            _ => None,
+        })
+        .unwrap_err();
+
+        assert_eq!(err.kind(), "config");
+        assert_eq!(
+            err.to_string(),
+            "VALIDATION_MAX_INVALID_KANA is invalid: many"
+        );
+    }
+}